@@ -17,6 +17,7 @@ const MAX_TOUCH_BAR_BRIGHTNESS: u32 = 255;
 const BRIGHTNESS_DIM_TIMEOUT: i32 = TIMEOUT_MS * 3; // should be a multiple of TIMEOUT_MS
 const BRIGHTNESS_OFF_TIMEOUT: i32 = TIMEOUT_MS * 6; // should be a multiple of TIMEOUT_MS
 const DIMMED_BRIGHTNESS: u32 = 1;
+const RAMP_STEP_MS: i32 = 16;
 
 fn read_attr(path: &Path, attr: &str) -> u32 {
     fs::read_to_string(path.join(attr))
@@ -55,17 +56,24 @@ fn set_backlight(mut file: &File, value: u32) {
 
 pub struct BacklightManager {
     last_active: Instant,
+    ramp_start: Option<Instant>,
+    // a touch woke the bar since the last update, which brings it up without a ramp
+    touched: bool,
     max_bl: u32,
     current_bl: u32,
     lid_state: SwitchState,
     bl_file: File,
-    display_bl_path: PathBuf
+    display_bl_path: PathBuf,
+    quiet: bool,
+    // set over the control socket, eg. while something is shown fullscreen
+    override_level: Option<f64>,
 }
 
 impl BacklightManager {
     pub fn new() -> BacklightManager {
-        let bl_path = find_backlight().unwrap();
-        let display_bl_path = find_display_backlight().unwrap();
+        BacklightManager::with_devices(&find_backlight().unwrap(), find_display_backlight().unwrap())
+    }
+    fn with_devices(bl_path: &Path, display_bl_path: PathBuf) -> BacklightManager {
         let bl_file = OpenOptions::new().write(true).open(bl_path.join("brightness")).unwrap();
         BacklightManager {
             bl_file,
@@ -73,7 +81,12 @@ impl BacklightManager {
             max_bl: read_attr(&bl_path, "max_brightness"),
             current_bl: read_attr(&bl_path, "brightness"),
             last_active: Instant::now(),
-            display_bl_path
+            // fade in from black on startup, whatever the backlight was left at
+            ramp_start: Some(Instant::now()),
+            touched: false,
+            display_bl_path,
+            quiet: false,
+            override_level: None,
         }
     }
     fn display_to_touchbar(display: u32, active_brightness: u32) -> u32 {
@@ -84,7 +97,8 @@ impl BacklightManager {
     }
     pub fn process_event(&mut self, event: &Event) {
         match event {
-            Event::Keyboard(_) | Event::Pointer(_) | Event::Gesture(_) | Event::Touch(_) => {
+            Event::Touch(_) => self.touched_at(Instant::now()),
+            Event::Keyboard(_) | Event::Pointer(_) | Event::Gesture(_) => {
                 self.last_active = Instant::now();
             },
             Event::Switch(SwitchEvent::Toggle(toggle)) => {
@@ -102,27 +116,195 @@ impl BacklightManager {
             _ => {}
         }
     }
+    fn touched_at(&mut self, now: Instant) {
+        self.last_active = now;
+        // don't make the user wait for the bar they are already using
+        self.ramp_start = None;
+        self.touched = true;
+    }
     pub fn update_backlight(&mut self, cfg: &Config) {
-        let since_last_active = (Instant::now() - self.last_active).as_millis() as u64;
-        let new_bl = min(self.max_bl, if self.lid_state == SwitchState::On {
-            0
-        } else if since_last_active < BRIGHTNESS_DIM_TIMEOUT as u64 {
-            if cfg.adaptive_brightness {
+        self.update_backlight_at(cfg, Instant::now())
+    }
+    // The brightness for how long the bar has been idle, before ramping up
+    fn target_bl(&self, cfg: &Config, since_last_active: u64) -> u32 {
+        let active_bl = || {
+            let active_bl = if cfg.adaptive_brightness {
                 BacklightManager::display_to_touchbar(read_attr(&self.display_bl_path, "brightness"), cfg.active_brightness)
             } else {
                 cfg.active_brightness
-            }
+            };
+            if self.quiet { min(active_bl, cfg.quiet_brightness) } else { active_bl }
+        };
+        // with a DimMin above 0 the touchbar never turns off completely
+        let mut new_bl = min(self.max_bl, if self.lid_state == SwitchState::On {
+            0
+        } else if let Some(level) = self.override_level {
+            (active_bl() as f64 * level) as u32
+        } else if since_last_active < BRIGHTNESS_DIM_TIMEOUT as u64 {
+            (active_bl() as f64 * cfg.dim_max) as u32
         } else if since_last_active < BRIGHTNESS_OFF_TIMEOUT as u64 {
-            DIMMED_BRIGHTNESS
+            DIMMED_BRIGHTNESS.max((active_bl() as f64 * cfg.dim_min) as u32)
         } else {
-            0
+            (active_bl() as f64 * cfg.dim_min) as u32
         });
+        if new_bl < cfg.min_brightness {
+            new_bl = 0;
+        }
+        new_bl
+    }
+    fn update_backlight_at(&mut self, cfg: &Config, now: Instant) {
+        let since_last_active = now.saturating_duration_since(self.last_active).as_millis() as u64;
+        let mut new_bl = self.target_bl(cfg, since_last_active);
+        if self.current_bl == 0 && new_bl != 0 && self.ramp_start.is_none() && !self.touched {
+            self.ramp_start = Some(now);
+        }
+        self.touched = false;
+        if let Some(start) = self.ramp_start {
+            let elapsed = now.saturating_duration_since(start).as_millis() as u32;
+            if new_bl == 0 || elapsed >= cfg.brightness_ramp_ms {
+                self.ramp_start = None;
+            } else {
+                let progress = elapsed as f64 / cfg.brightness_ramp_ms as f64;
+                // anything below MinBrightness would only flicker, like above
+                new_bl = ((new_bl as f64 * progress) as u32).max(cfg.min_brightness.max(1));
+            }
+        }
         if self.current_bl != new_bl {
             self.current_bl = new_bl;
             set_backlight(&self.bl_file, self.current_bl);
         }
     }
+    // Quiet mode caps the brightness, which is worked out anew on every update,
+    // so it goes back to the usual one on its own when turned off
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+    // A level set from outside takes the place of dimming after being idle until
+    // it is reset, which only still turns the touchbar off with the lid closed
+    pub fn set_override(&mut self, level: Option<f64>) {
+        self.override_level = level;
+    }
+    pub fn override_level(&self) -> Option<f64> {
+        self.override_level
+    }
+    // Keeps the touchbar from dimming while something happens that produces no events
+    pub fn keep_awake(&mut self) {
+        self.keep_awake_at(Instant::now())
+    }
+    fn keep_awake_at(&mut self, now: Instant) {
+        self.last_active = now;
+    }
+    // Whether being idle made the touchbar darker than it is while used, which an
+    // override, quiet mode or a DimMin at DimMax can keep from happening.
+    // Needs to be checked before the event that woke the touchbar is processed.
+    pub fn is_dimmed(&self, cfg: &Config) -> bool {
+        self.is_dimmed_at(cfg, Instant::now())
+    }
+    fn is_dimmed_at(&self, cfg: &Config, now: Instant) -> bool {
+        let since_last_active = now.saturating_duration_since(self.last_active).as_millis() as u64;
+        self.target_bl(cfg, since_last_active) < self.target_bl(cfg, 0)
+    }
     pub fn current_bl(&self) -> u32 {
         self.current_bl
     }
+    pub fn next_timeout_ms(&self) -> i32 {
+        if self.ramp_start.is_some() {
+            RAMP_STEP_MS
+        } else {
+            i32::MAX
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn backlight(dir: &str) -> BacklightManager {
+        let path = std::env::temp_dir().join(format!("tiny-dfr-{}-{}", dir, std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("max_brightness"), "255\n").unwrap();
+        fs::write(path.join("brightness"), "0\n").unwrap();
+        let mut backlight = BacklightManager::with_devices(&path, path.clone());
+        backlight.ramp_start = None;
+        backlight
+    }
+
+    // The main loop keeps the bar awake on every wakeup while a button is held,
+    // which happens at least every TIMEOUT_MS
+    #[test]
+    fn held_button_keeps_the_bar_bright() {
+        let mut cfg = crate::config::test_config();
+        cfg.adaptive_brightness = false;
+        cfg.brightness_ramp_ms = 0;
+        let mut backlight = backlight("held");
+        let start = Instant::now();
+        backlight.keep_awake_at(start);
+        backlight.update_backlight_at(&cfg, start);
+        let bright = backlight.current_bl();
+        assert!(bright > 0);
+        let mut now = start;
+        while now < start + Duration::from_millis(BRIGHTNESS_OFF_TIMEOUT as u64 * 3) {
+            now += Duration::from_millis(TIMEOUT_MS as u64);
+            backlight.keep_awake_at(now);
+            backlight.update_backlight_at(&cfg, now);
+            assert_eq!(backlight.current_bl(), bright);
+        }
+        // and dims once it is let go
+        now += Duration::from_millis(BRIGHTNESS_DIM_TIMEOUT as u64);
+        backlight.update_backlight_at(&cfg, now);
+        assert!(backlight.current_bl() < bright);
+    }
+
+    #[test]
+    fn touch_wakes_the_bar_without_a_ramp() {
+        let mut cfg = crate::config::test_config();
+        cfg.adaptive_brightness = false;
+        cfg.brightness_ramp_ms = 1000;
+        cfg.dim_min = 0.0;
+        let mut backlight = backlight("touch");
+        let bright = min(255, (cfg.active_brightness as f64 * cfg.dim_max) as u32);
+        let start = Instant::now();
+        let off = start + Duration::from_millis(BRIGHTNESS_OFF_TIMEOUT as u64 * 2);
+        backlight.update_backlight_at(&cfg, off);
+        assert_eq!(backlight.current_bl(), 0);
+        backlight.touched_at(off);
+        backlight.update_backlight_at(&cfg, off);
+        assert_eq!(backlight.current_bl(), bright);
+        assert!(backlight.ramp_start.is_none());
+        // anything else still ramps up
+        let off = off + Duration::from_millis(BRIGHTNESS_OFF_TIMEOUT as u64 * 2);
+        backlight.update_backlight_at(&cfg, off);
+        backlight.keep_awake_at(off);
+        backlight.update_backlight_at(&cfg, off);
+        assert!(backlight.ramp_start.is_some());
+        assert!(backlight.current_bl() < bright);
+    }
+
+    #[test]
+    fn dimmed_only_when_idle_makes_the_bar_darker() {
+        let mut cfg = crate::config::test_config();
+        cfg.adaptive_brightness = false;
+        cfg.dim_min = 0.0;
+        cfg.dim_max = 1.0;
+        let mut backlight = backlight("dimmed");
+        let start = Instant::now();
+        backlight.keep_awake_at(start);
+        let idle = start + Duration::from_millis(BRIGHTNESS_OFF_TIMEOUT as u64);
+        assert!(!backlight.is_dimmed_at(&cfg, start));
+        assert!(backlight.is_dimmed_at(&cfg, idle));
+        // the override stays the same however long the bar is idle
+        backlight.set_override(Some(0.5));
+        assert!(!backlight.is_dimmed_at(&cfg, idle));
+        backlight.set_override(None);
+        // as does a DimMin at DimMax
+        cfg.dim_min = 1.0;
+        assert!(!backlight.is_dimmed_at(&cfg, idle));
+        // quiet mode darkens the bar while it is used too
+        cfg.dim_min = 0.0;
+        backlight.set_quiet(true);
+        assert!(backlight.is_dimmed_at(&cfg, idle));
+        assert!(!backlight.is_dimmed_at(&cfg, start));
+    }
 }
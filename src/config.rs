@@ -1,132 +1,566 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     fs::read_to_string,
+    io::ErrorKind,
     os::fd::AsFd
 };
-use anyhow::Error;
+use anyhow::{Result, anyhow};
 use cairo::FontFace;
 use crate::{FunctionLayer, Button};
 use crate::fonts::{FontConfig, Pattern};
+use crate::event_log::{self, Record};
 use freetype::Library as FtLibrary;
 use input_linux::Key;
 use nix::{
     errno::Errno,
     sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor}
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 const USER_CFG_PATH: &'static str = "/etc/tiny-dfr/config.toml";
+const SHARE_CFG_PATH: &'static str = "/usr/share/tiny-dfr/config.toml";
+const BUILTIN_CONFIG: &'static str = include_str!("builtin_config.toml");
 
 pub struct Config {
     pub show_button_outlines: bool,
     pub enable_pixel_shift: bool,
-    pub font_face: FontFace,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub edge_to_edge: bool,
+    pub divider_width: u32,
+    // the font is loaded from this by the render threads
+    pub font_template: String,
     pub adaptive_brightness: bool,
     pub active_brightness: u32,
+    pub brightness_ramp_ms: u32,
+    pub dim_min: f64,
+    pub dim_max: f64,
+    pub min_brightness: u32,
+    pub quiet_brightness: u32,
+    pub quiet_locks_layer: bool,
+    pub wake_swallows_touch: bool,
+    pub replay_early_touches: bool,
     pub button_style: ButtonStyle,
+    pub stats_file: Option<String>,
+    pub session_stats_file: Option<String>,
+    pub state_file: Option<String>,
+    pub control_group: Option<String>,
+    pub led_indicators: HashMap<String, usize>,
+    pub slider_coalesce_window_ms: u32,
+    pub slider_max_taps: u32,
+    pub display_watchdog_interval_ms: u32,
+    pub repeat_keys: Option<Vec<Key>>,
+    pub tap_dead_time_ms: u32,
+    pub two_finger_peek: bool,
+    pub hold_preview_ms: u32,
+    pub hold_progress_line: bool,
+    pub gesture_cooldown_ms: u32,
+    pub max_touches: usize,
+    pub extra_touches: ExtraTouches,
+    pub frame_interval_ms: u32,
+    pub layer_fade_ms: u32,
+    pub keep_awake_while_held: bool,
+    pub layer_indicator: LayerIndicator,
+    pub layer_indicator_position: IndicatorPosition,
+    pub missing_uinput: MissingUinput,
+    pub advertise_keyboard: bool,
+    pub vt_switch: VtSwitch,
+    pub session_vt: u32,
+    pub docked_keyboard: Option<String>,
+    // whether the other layer is shown without Fn while docked
+    pub docked_swaps_layers: bool,
+    pub layer_key: Key,
+    // the merged config files as they were parsed, for state dumps
+    pub source: serde_json::Value,
 }
 
 #[derive(Clone, Copy)]
 pub struct ButtonStyle {
     pub inactive_color: (f64, f64, f64),
     pub active_color: (f64, f64, f64),
+    pub indicator_color: (f64, f64, f64),
+    pub warning_color: (f64, f64, f64),
+    pub accent_color: (f64, f64, f64),
+    pub active_effect: ActiveEffect,
     pub on_time: f64,
     pub off_time: f64,
     pub bounce: f64,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 struct ConfigProxy {
     media_layer_default: Option<bool>,
     show_button_outlines: Option<bool>,
     enable_pixel_shift: Option<bool>,
+    flip_horizontal: Option<bool>,
+    flip_vertical: Option<bool>,
+    edge_to_edge: Option<bool>,
+    divider_width: Option<u32>,
     font_template: Option<String>,
     adaptive_brightness: Option<bool>,
     active_brightness: Option<u32>,
+    brightness_ramp_duration: Option<u32>,
+    dim_min: Option<f64>,
+    dim_max: Option<f64>,
+    min_brightness: Option<u32>,
+    quiet_brightness: Option<u32>,
+    quiet_locks_layer: Option<bool>,
+    wake_swallows_touch: Option<bool>,
+    replay_early_touches: Option<bool>,
     primary_layer_keys: Option<Vec<ButtonConfig>>,
     media_layer_keys: Option<Vec<ButtonConfig>>,
     button_style: Option<ButtonStyleProxy>,
+    stats_file: Option<String>,
+    session_stats_file: Option<String>,
+    state_file: Option<String>,
+    control_group: Option<String>,
+    led_indicators: Option<HashMap<String, usize>>,
+    slider_coalesce_window: Option<u32>,
+    slider_max_taps: Option<u32>,
+    display_watchdog_interval: Option<u32>,
+    repeat_keys: Option<Vec<Key>>,
+    tap_dead_time: Option<u32>,
+    two_finger_peek: Option<bool>,
+    hold_preview_delay: Option<u32>,
+    hold_progress_line: Option<bool>,
+    gesture_cooldown: Option<u32>,
+    max_touches: Option<usize>,
+    extra_touches: Option<ExtraTouches>,
+    frame_interval: Option<u32>,
+    layer_fade_duration: Option<u32>,
+    keep_awake_while_held: Option<bool>,
+    layer_indicator: Option<LayerIndicator>,
+    layer_indicator_position: Option<IndicatorPosition>,
+    missing_uinput: Option<MissingUinput>,
+    advertise_keyboard: Option<bool>,
+    vt_switch: Option<VtSwitch>,
+    session_vt: Option<u32>,
+    docked_keyboard: Option<String>,
+    docked_media_layer_default: Option<bool>,
+    layer_key: Option<Key>,
+    extra_layers: Option<BTreeMap<String, Vec<ButtonConfig>>>,
+    layer_feedback: Option<BTreeMap<String, Feedback>>,
 }
 
-#[derive(Deserialize, Clone, Copy)]
+// How a button responds to being pressed. Set for a whole layer in LayerFeedback
+// and per button in Feedback, where unset fields fall back to the layer.
+// Only the flash is supported, there is no haptic or sound feedback to configure.
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Feedback {
+    pub flash: Option<bool>,
+}
+
+impl Feedback {
+    pub fn or(self, fallback: Feedback) -> Feedback {
+        Feedback {
+            flash: self.flash.or(fallback.flash),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ActiveEffect {
+    None,
+    Border,
+    Glow,
+    Inset,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum LayerIndicator {
+    None,
+    Dots,
+    Label,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum IndicatorPosition {
+    Left,
+    Right,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ExtraTouches {
+    Ignore,
+    CancelAll,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum VtSwitch {
+    Keep,
+    Release,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum MissingUinput {
+    Retry,
+    DisplayOnly,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
 #[serde(rename_all = "PascalCase")]
 pub struct ButtonStyleProxy {
     pub inactive_color: Option<(f64, f64, f64)>,
     pub active_color: Option<(f64, f64, f64)>,
+    pub indicator_color: Option<(f64, f64, f64)>,
+    pub warning_color: Option<(f64, f64, f64)>,
+    pub accent_color: Option<(f64, f64, f64)>,
+    pub active_effect: Option<ActiveEffect>,
     pub on_time: Option<f64>,
     pub off_time: Option<f64>,
     pub bounce: Option<f64>,
 }
 
-#[derive(Deserialize)]
+impl ButtonStyleProxy {
+    fn or(self, base: ButtonStyleProxy) -> ButtonStyleProxy {
+        ButtonStyleProxy {
+            inactive_color: self.inactive_color.or(base.inactive_color),
+            active_color: self.active_color.or(base.active_color),
+            indicator_color: self.indicator_color.or(base.indicator_color),
+            warning_color: self.warning_color.or(base.warning_color),
+            accent_color: self.accent_color.or(base.accent_color),
+            active_effect: self.active_effect.or(base.active_effect),
+            on_time: self.on_time.or(base.on_time),
+            off_time: self.off_time.or(base.off_time),
+            bounce: self.bounce.or(base.bounce),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub enum Action {
+    Text(String),
+    MouseButton(Key),
+    Scroll(i32),
+    // shows the layer with the given name until another Layer action is used
+    Layer(String),
+    // writes the current frame and config to /tmp, for bug reports
+    DumpState,
+    // runs a shell command on press, as the unprivileged user tiny-dfr runs as
+    Command(String),
+    // turns quiet mode on or off
+    Quiet,
+    #[serde(untagged)]
+    Key(Key),
+}
+
+impl Action {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Action::Text(_) => "text",
+            Action::MouseButton(_) => "mouse_button",
+            Action::Scroll(_) => "scroll",
+            Action::Layer(_) => "layer",
+            Action::DumpState => "dump_state",
+            Action::Command(_) => "command",
+            Action::Quiet => "quiet",
+            Action::Key(_) => "key",
+        }
+    }
+    // Short description of what the action does, to show on the touchbar
+    pub fn describe(&self) -> String {
+        match self {
+            Action::Text(text) => text.clone(),
+            Action::MouseButton(key) | Action::Key(key) => format!("{:?}", key),
+            Action::Scroll(amount) => format!("Scroll {}", amount),
+            Action::Layer(name) => name.clone(),
+            Action::DumpState => "DumpState".to_string(),
+            Action::Command(command) => command.clone(),
+            Action::Quiet => "Quiet".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct ButtonConfig {
     #[serde(alias = "Svg")]
     pub icon: Option<String>,
     pub text: Option<String>,
-    pub action: Key
+    // every button needs an Action, except for Cycle buttons
+    pub action: Option<Action>,
+    pub slider: Option<SliderConfig>,
+    pub group: Option<String>,
+    pub repeat: Option<bool>,
+    pub tap_dead_time: Option<u32>,
+    pub confirm: Option<bool>,
+    pub shift_action: Option<Action>,
+    pub ctrl_action: Option<Action>,
+    pub alt_action: Option<Action>,
+    pub two_finger_action: Option<Action>,
+    pub cycle: Option<Vec<CycleEntry>>,
+    pub cycle_query: Option<String>,
+    pub feedback: Option<Feedback>,
+    pub icon_color: Option<(f64, f64, f64)>,
+    pub visible_when: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct CycleEntry {
+    pub text: String,
+    pub action: Action,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct SliderConfig {
+    pub decrease: Key,
+    pub increase: Key,
+    pub step: Option<f64>,
+    pub dwell: Option<u32>,
 }
 
-fn load_font(name: &str) -> FontFace {
+pub fn load_font(name: &str) -> Result<FontFace> {
     let fontconfig = FontConfig::new();
     let mut pattern = Pattern::new(name);
     fontconfig.perform_substitutions(&mut pattern);
     let pat_match = match fontconfig.match_pattern(&pattern) {
         Ok(pat) => pat,
-        Err(_) => panic!("Unable to find specified font. If you are using the default config, make sure you have at least one font installed")
+        Err(_) => return Err(anyhow!("Unable to find specified font. If you are using the default config, make sure you have at least one font installed"))
     };
     let file_name = pat_match.get_file_name();
     let file_idx = pat_match.get_font_index();
-    let ft_library = FtLibrary::init().unwrap();
-    let face = ft_library.new_face(file_name, file_idx).unwrap();
-    FontFace::create_from_ft(&face).unwrap()
-}
-
-fn load_config(width: u16) -> (Config, [FunctionLayer; 2]) {
-    let mut base = toml::from_str::<ConfigProxy>(&read_to_string("/usr/share/tiny-dfr/config.toml").unwrap()).unwrap();
-    let user = read_to_string(USER_CFG_PATH).map_err::<Error, _>(|e| e.into())
-        .and_then(|r| Ok(toml::from_str::<ConfigProxy>(&r)?));
-    if let Ok(user) = user {
-        base.media_layer_default = user.media_layer_default.or(base.media_layer_default);
-        base.show_button_outlines = user.show_button_outlines.or(base.show_button_outlines);
-        base.enable_pixel_shift = user.enable_pixel_shift.or(base.enable_pixel_shift);
-        base.font_template = user.font_template.or(base.font_template);
-        base.adaptive_brightness = user.adaptive_brightness.or(base.adaptive_brightness);
-        base.media_layer_keys = user.media_layer_keys.or(base.media_layer_keys);
-        base.primary_layer_keys = user.primary_layer_keys.or(base.primary_layer_keys);
-        base.active_brightness = user.active_brightness.or(base.active_brightness);
-        base.active_brightness = user.active_brightness.or(base.active_brightness);
-        base.button_style = user.button_style.or(base.button_style);
-        base.button_style.unwrap().inactive_color = user.button_style.and_then(|s| s.inactive_color).or(base.button_style.unwrap().inactive_color);
-        base.button_style.unwrap().active_color = user.button_style.and_then(|s| s.active_color).or(base.button_style.unwrap().active_color);
-        base.button_style.unwrap().on_time = user.button_style.and_then(|s| s.on_time).or(base.button_style.unwrap().on_time);
-        base.button_style.unwrap().off_time = user.button_style.and_then(|s| s.off_time).or(base.button_style.unwrap().off_time);
-        base.button_style.unwrap().bounce = user.button_style.and_then(|s| s.bounce).or(base.button_style.unwrap().bounce);
-    };
-    let media_layer = FunctionLayer::with_config(base.media_layer_keys.unwrap());
-    let fkey_layer = FunctionLayer::with_config(base.primary_layer_keys.unwrap());
-    let mut layers = if base.media_layer_default.unwrap(){ [media_layer, fkey_layer] } else { [fkey_layer, media_layer] };
+    let ft_library = FtLibrary::init()?;
+    let face = ft_library.new_face(file_name, file_idx)?;
+    Ok(FontFace::create_from_ft(&face)?)
+}
+
+fn add_esc_key(layers: &mut [FunctionLayer], width: u16) {
     if width >= 2170 {
-        for layer in &mut layers {
-            layer.buttons.insert(0, Button::new_text("esc".to_string(), Key::Esc));
+        for layer in layers {
+            layer.buttons.insert(0, Button::new_text("esc".to_string(), Action::Key(Key::Esc)));
+        }
+    }
+}
+
+fn load_config(user_path: &str, widths: &[u16]) -> Result<(Config, Vec<Vec<FunctionLayer>>)> {
+    // the shipped config goes over the embedded one, so that an option it
+    // lacks, eg. in an older copy, still has a value
+    let mut base = toml::from_str::<ConfigProxy>(BUILTIN_CONFIG).unwrap();
+    if let Some(shipped) = read_config(SHARE_CFG_PATH)? {
+        base = merge_config(base, shipped);
+    }
+    build_config(base, read_config(user_path)?, widths)
+}
+
+fn read_config(path: &str) -> Result<Option<ConfigProxy>> {
+    match read_to_string(path) {
+        Ok(r) => Ok(Some(toml::from_str::<ConfigProxy>(&r).map_err(|e| anyhow!("{}: {}", path, e))?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("{}: {}", path, e)),
+    }
+}
+
+// Takes every option set in `user` over the one in `base`
+fn merge_config(mut base: ConfigProxy, user: ConfigProxy) -> ConfigProxy {
+    base.media_layer_default = user.media_layer_default.or(base.media_layer_default);
+    base.show_button_outlines = user.show_button_outlines.or(base.show_button_outlines);
+    base.enable_pixel_shift = user.enable_pixel_shift.or(base.enable_pixel_shift);
+    base.flip_horizontal = user.flip_horizontal.or(base.flip_horizontal);
+    base.flip_vertical = user.flip_vertical.or(base.flip_vertical);
+    base.edge_to_edge = user.edge_to_edge.or(base.edge_to_edge);
+    base.divider_width = user.divider_width.or(base.divider_width);
+    base.font_template = user.font_template.or(base.font_template);
+    base.adaptive_brightness = user.adaptive_brightness.or(base.adaptive_brightness);
+    base.media_layer_keys = user.media_layer_keys.or(base.media_layer_keys);
+    base.primary_layer_keys = user.primary_layer_keys.or(base.primary_layer_keys);
+    base.active_brightness = user.active_brightness.or(base.active_brightness);
+    base.active_brightness = user.active_brightness.or(base.active_brightness);
+    base.brightness_ramp_duration = user.brightness_ramp_duration.or(base.brightness_ramp_duration);
+    base.dim_min = user.dim_min.or(base.dim_min);
+    base.dim_max = user.dim_max.or(base.dim_max);
+    base.min_brightness = user.min_brightness.or(base.min_brightness);
+    base.quiet_brightness = user.quiet_brightness.or(base.quiet_brightness);
+    base.quiet_locks_layer = user.quiet_locks_layer.or(base.quiet_locks_layer);
+    base.button_style = match (user.button_style, base.button_style) {
+        (Some(user), Some(base)) => Some(user.or(base)),
+        (user, base) => user.or(base),
+    };
+    base.stats_file = user.stats_file.or(base.stats_file);
+    base.session_stats_file = user.session_stats_file.or(base.session_stats_file);
+    base.state_file = user.state_file.or(base.state_file);
+    base.control_group = user.control_group.or(base.control_group);
+    base.led_indicators = user.led_indicators.or(base.led_indicators);
+    base.slider_coalesce_window = user.slider_coalesce_window.or(base.slider_coalesce_window);
+    base.slider_max_taps = user.slider_max_taps.or(base.slider_max_taps);
+    base.wake_swallows_touch = user.wake_swallows_touch.or(base.wake_swallows_touch);
+    base.replay_early_touches = user.replay_early_touches.or(base.replay_early_touches);
+    base.display_watchdog_interval = user.display_watchdog_interval.or(base.display_watchdog_interval);
+    base.repeat_keys = user.repeat_keys.or(base.repeat_keys);
+    base.tap_dead_time = user.tap_dead_time.or(base.tap_dead_time);
+    base.two_finger_peek = user.two_finger_peek.or(base.two_finger_peek);
+    base.hold_preview_delay = user.hold_preview_delay.or(base.hold_preview_delay);
+    base.hold_progress_line = user.hold_progress_line.or(base.hold_progress_line);
+    base.gesture_cooldown = user.gesture_cooldown.or(base.gesture_cooldown);
+    base.max_touches = user.max_touches.or(base.max_touches);
+    base.extra_touches = user.extra_touches.or(base.extra_touches);
+    base.frame_interval = user.frame_interval.or(base.frame_interval);
+    base.layer_fade_duration = user.layer_fade_duration.or(base.layer_fade_duration);
+    base.keep_awake_while_held = user.keep_awake_while_held.or(base.keep_awake_while_held);
+    base.layer_indicator = user.layer_indicator.or(base.layer_indicator);
+    base.layer_indicator_position = user.layer_indicator_position.or(base.layer_indicator_position);
+    base.missing_uinput = user.missing_uinput.or(base.missing_uinput);
+    base.advertise_keyboard = user.advertise_keyboard.or(base.advertise_keyboard);
+    base.vt_switch = user.vt_switch.or(base.vt_switch);
+    base.session_vt = user.session_vt.or(base.session_vt);
+    base.docked_keyboard = user.docked_keyboard.or(base.docked_keyboard);
+    base.docked_media_layer_default = user.docked_media_layer_default.or(base.docked_media_layer_default);
+    base.layer_key = user.layer_key.or(base.layer_key);
+    base.extra_layers = user.extra_layers.or(base.extra_layers);
+    base.layer_feedback = user.layer_feedback.or(base.layer_feedback);
+    base
+}
+
+// Merges the user config over the base one and builds the layers from the result.
+// Every touchbar gets its own copy of the layers, laid out for its width
+fn build_config(mut base: ConfigProxy, user: Option<ConfigProxy>, widths: &[u16]) -> Result<(Config, Vec<Vec<FunctionLayer>>)> {
+    if let Some(user) = user {
+        base = merge_config(base, user);
+    }
+    let source = serde_json::to_value(&base)?;
+    let media_layer_keys = base.media_layer_keys.unwrap();
+    let primary_layer_keys = base.primary_layer_keys.unwrap();
+    let media_layer_default = base.media_layer_default.unwrap();
+    let extra_layers = base.extra_layers.unwrap_or_default();
+    for name in extra_layers.keys() {
+        if name == "primary" || name == "media" {
+            return Err(anyhow!("ExtraLayers can not redefine the {} layer", name));
+        }
+    }
+    let layer_feedback = base.layer_feedback.clone().unwrap_or_default();
+    for name in layer_feedback.keys() {
+        if name != "primary" && name != "media" && !extra_layers.contains_key(name) {
+            return Err(anyhow!("Unknown layer in LayerFeedback: {}", name));
+        }
+    }
+    let feedback_for = |name: &str| layer_feedback.get(name).copied().unwrap_or_default();
+    let all_keys = primary_layer_keys.iter().chain(&media_layer_keys).chain(extra_layers.values().flatten());
+    for button in all_keys {
+        let cycle_actions = button.cycle.iter().flatten().map(|entry| &entry.action);
+        for action in button.action.iter().chain(&button.shift_action).chain(&button.ctrl_action).chain(&button.alt_action).chain(&button.two_finger_action).chain(cycle_actions) {
+            if let Action::Layer(name) = action {
+                if name != "primary" && name != "media" && !extra_layers.contains_key(name) {
+                    return Err(anyhow!("Unknown layer in Layer action: {}", name));
+                }
+            }
         }
     }
+    // The first two layers are the default and Fn layer, the extra layers follow
+    let layers = widths.iter().map(|&width| {
+        let media_layer = FunctionLayer::with_config("media", media_layer_keys.clone(), feedback_for("media"))?;
+        let fkey_layer = FunctionLayer::with_config("primary", primary_layer_keys.clone(), feedback_for("primary"))?;
+        let mut layers = if media_layer_default { vec![media_layer, fkey_layer] } else { vec![fkey_layer, media_layer] };
+        for (name, keys) in &extra_layers {
+            layers.push(FunctionLayer::with_config(name, keys.clone(), feedback_for(name))?);
+        }
+        add_esc_key(&mut layers, width);
+        Ok(layers)
+    }).collect::<Result<Vec<_>>>()?;
     let button_style = ButtonStyle {
         inactive_color: base.button_style.unwrap().inactive_color.unwrap(),
         active_color: base.button_style.unwrap().active_color.unwrap(),
+        indicator_color: base.button_style.unwrap().indicator_color.unwrap(),
+        warning_color: base.button_style.unwrap().warning_color.unwrap(),
+        accent_color: base.button_style.unwrap().accent_color.unwrap(),
+        active_effect: base.button_style.unwrap().active_effect.unwrap(),
         on_time: base.button_style.unwrap().on_time.unwrap(),
         off_time: base.button_style.unwrap().off_time.unwrap(),
         bounce: base.button_style.unwrap().bounce.unwrap().clamp(-5., 5.),
     };
+    // only to find out whether it exists, the render threads load their own
+    let font_template = base.font_template.unwrap();
+    load_font(&font_template)?;
+    // TOML allows nan, which clamp would keep or panic on
+    let dim_max = match base.dim_max.unwrap() {
+        dim_max if dim_max.is_nan() => 1.0,
+        dim_max => dim_max.clamp(0.0, 1.0),
+    };
+    let dim_min = match base.dim_min.unwrap() {
+        dim_min if dim_min.is_nan() => 0.0,
+        dim_min => dim_min.clamp(0.0, dim_max),
+    };
+    if dim_min != base.dim_min.unwrap() || dim_max != base.dim_max.unwrap() {
+        println!("DimMin and DimMax must satisfy 0 <= DimMin <= DimMax <= 1, using {} and {}", dim_min, dim_max);
+    }
     let cfg = Config {
         show_button_outlines: base.show_button_outlines.unwrap(),
         enable_pixel_shift: base.enable_pixel_shift.unwrap(),
+        flip_horizontal: base.flip_horizontal.unwrap(),
+        flip_vertical: base.flip_vertical.unwrap(),
+        edge_to_edge: base.edge_to_edge.unwrap(),
+        divider_width: base.divider_width.unwrap(),
         adaptive_brightness: base.adaptive_brightness.unwrap(),
-        font_face: load_font(&base.font_template.unwrap()),
+        font_template,
         active_brightness: base.active_brightness.unwrap(),
+        brightness_ramp_ms: base.brightness_ramp_duration.unwrap(),
+        dim_min,
+        dim_max,
+        min_brightness: base.min_brightness.unwrap(),
+        quiet_brightness: base.quiet_brightness.unwrap(),
+        quiet_locks_layer: base.quiet_locks_layer.unwrap(),
+        wake_swallows_touch: base.wake_swallows_touch.unwrap(),
+        replay_early_touches: base.replay_early_touches.unwrap(),
         button_style,
+        stats_file: base.stats_file,
+        session_stats_file: base.session_stats_file,
+        state_file: base.state_file,
+        control_group: base.control_group,
+        led_indicators: base.led_indicators.unwrap_or_default(),
+        slider_coalesce_window_ms: base.slider_coalesce_window.unwrap(),
+        slider_max_taps: base.slider_max_taps.unwrap().max(1),
+        display_watchdog_interval_ms: base.display_watchdog_interval.unwrap(),
+        repeat_keys: base.repeat_keys,
+        tap_dead_time_ms: base.tap_dead_time.unwrap(),
+        two_finger_peek: base.two_finger_peek.unwrap(),
+        hold_preview_ms: base.hold_preview_delay.unwrap(),
+        hold_progress_line: base.hold_progress_line.unwrap(),
+        gesture_cooldown_ms: base.gesture_cooldown.unwrap(),
+        max_touches: base.max_touches.unwrap().max(1),
+        extra_touches: base.extra_touches.unwrap(),
+        frame_interval_ms: base.frame_interval.unwrap(),
+        layer_fade_ms: base.layer_fade_duration.unwrap(),
+        keep_awake_while_held: base.keep_awake_while_held.unwrap(),
+        layer_indicator: base.layer_indicator.unwrap(),
+        layer_indicator_position: base.layer_indicator_position.unwrap(),
+        missing_uinput: base.missing_uinput.unwrap(),
+        advertise_keyboard: base.advertise_keyboard.unwrap(),
+        vt_switch: base.vt_switch.unwrap(),
+        session_vt: base.session_vt.unwrap(),
+        source,
+        docked_keyboard: base.docked_keyboard,
+        docked_swaps_layers: base.docked_media_layer_default.map_or(false, |docked| docked != media_layer_default),
+        layer_key: base.layer_key.unwrap(),
     };
-    (cfg, layers)
+    Ok((cfg, layers))
+}
+
+// Used when the config can not be loaded, so that the touchbar stays usable
+fn builtin_config(widths: &[u16]) -> (Config, Vec<Vec<FunctionLayer>>) {
+    let base = toml::from_str::<ConfigProxy>(BUILTIN_CONFIG).unwrap();
+    build_config(base, None, widths).unwrap()
+}
+
+// The builtin config, for tests of the main loop that need one
+#[cfg(test)]
+pub fn test_config() -> Config {
+    builtin_config(&[2008]).0
+}
+
+// The builtin config with `user` merged over it and its layers on a bar of the given width
+#[cfg(test)]
+pub fn test_layers(user: &str, width: u16) -> (Config, Vec<FunctionLayer>) {
+    let base = toml::from_str::<ConfigProxy>(BUILTIN_CONFIG).unwrap();
+    let user = toml::from_str::<ConfigProxy>(user).unwrap();
+    let (cfg, mut layers) = build_config(base, Some(user), &[width]).unwrap();
+    (cfg, layers.remove(0))
+}
+
+pub fn check_config(path: Option<&str>) -> Result<()> {
+    load_config(path.unwrap_or(USER_CFG_PATH), &[0])?;
+    Ok(())
 }
 
 pub struct ConfigManager {
@@ -151,10 +585,17 @@ impl ConfigManager {
             inotify_fd, watch_desc
         }
     }
-    pub fn load_config(&self, width: u16) -> (Config, [FunctionLayer; 2]) {
-        load_config(width)
+    pub fn load_config(&self, widths: &[u16]) -> (Config, Vec<Vec<FunctionLayer>>) {
+        match load_config(USER_CFG_PATH, widths) {
+            Ok(parts) => parts,
+            Err(e) => {
+                println!("Error while loading config: {:#}", e);
+                println!("The config was ignored due to errors, using the built-in default layout instead");
+                builtin_config(widths)
+            }
+        }
     }
-    pub fn update_config(&mut self, cfg: &mut Config, layers: &mut [FunctionLayer; 2], width: u16) -> bool {
+    pub fn update_config(&mut self, cfg: &mut Config, layers: &mut Vec<Vec<FunctionLayer>>, widths: &[u16]) -> bool {
         if self.watch_desc.is_none() {
             self.watch_desc = arm_inotify(&self.inotify_fd);
             return false;
@@ -169,10 +610,18 @@ impl ConfigManager {
             if evt.wd != self.watch_desc.unwrap() {
                 continue
             }
-            let parts = load_config(width);
-            *cfg = parts.0;
-            *layers = parts.1;
-            ret = true;
+            match load_config(USER_CFG_PATH, widths) {
+                Ok((new_cfg, mut new_layers)) => {
+                    crate::sync_cycles(&mut new_layers, layers);
+                    *cfg = new_cfg;
+                    *layers = new_layers;
+                    ret = true;
+                }
+                Err(e) => {
+                    println!("Error while reloading config, keeping the previous one: {:#}", e);
+                    event_log::record(Record::Error("config reload failed"));
+                }
+            }
             self.watch_desc = arm_inotify(&self.inotify_fd);
         }
         ret
@@ -181,3 +630,39 @@ impl ConfigManager {
         &self.inotify_fd
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A key missing from builtin_config.toml only panics once the user
+    // config fails to load, which is exactly when it is needed
+    #[test]
+    fn builtin_config_builds_on_its_own() {
+        let base = toml::from_str::<ConfigProxy>(BUILTIN_CONFIG).unwrap();
+        let (_, layers) = build_config(base, None, &[2008, 2170]).unwrap();
+        assert_eq!(layers.len(), 2);
+        assert!(layers.iter().all(|layers| !layers.is_empty()));
+    }
+
+    // What gets installed to /usr/share, merged over the builtin config as load_config does.
+    // Its icons are only found once installed, so the media layer is replaced by text.
+    #[test]
+    fn shipped_config_builds() {
+        let builtin = toml::from_str::<ConfigProxy>(BUILTIN_CONFIG).unwrap();
+        let base = merge_config(builtin, toml::from_str::<ConfigProxy>(include_str!("../share/tiny-dfr/config.toml")).unwrap());
+        let user = toml::from_str::<ConfigProxy>("MediaLayerKeys = [{ Text = \"Mute\", Action = \"Mute\" }]").unwrap();
+        let (_, layers) = build_config(base, Some(user), &[2008]).unwrap();
+        assert!(layers[0].len() >= 2);
+    }
+
+    #[test]
+    fn dim_range_survives_values_that_are_not_numbers() {
+        for (dim_min, dim_max, expected) in [("nan", "nan", (0.0, 1.0)), ("-inf", "inf", (0.0, 1.0)), ("0.5", "nan", (0.5, 1.0)), ("nan", "0.5", (0.0, 0.5))] {
+            let base = toml::from_str::<ConfigProxy>(BUILTIN_CONFIG).unwrap();
+            let user = toml::from_str::<ConfigProxy>(&format!("DimMin = {}\nDimMax = {}", dim_min, dim_max)).unwrap();
+            let (cfg, _) = build_config(base, Some(user), &[2008]).unwrap();
+            assert_eq!((cfg.dim_min, cfg.dim_max), expected);
+        }
+    }
+}
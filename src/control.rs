@@ -0,0 +1,337 @@
+use std::{
+    collections::VecDeque,
+    ffi::CString,
+    fs::{self, Permissions},
+    io::{self, ErrorKind, Read, Write},
+    os::{
+        fd::AsFd,
+        unix::{
+            fs::{self as unix_fs, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    time::Duration,
+};
+use anyhow::{Result, anyhow};
+use nix::sys::epoll::{Epoll, EpollEvent, EpollFlags};
+use serde_json::json;
+
+const SOCKET_PATH: &'static str = "/run/tiny-dfr.sock";
+const MAX_REQUEST_LEN: usize = 256;
+const CLIENT_TIMEOUT_MS: u64 = 2000;
+const MAX_QUEUED_EVENTS: usize = 64;
+
+pub enum Command {
+    Status,
+    ResetStats,
+    Subscribe,
+    DebugTouch(bool),
+    Quiet(bool),
+    // a level between 0 and 1 used instead of dimming, None goes back to dimming
+    SetBrightness(Option<f64>),
+    SetFlag(String, bool),
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Command> {
+        match line.trim() {
+            "status" => Ok(Command::Status),
+            "reset-stats" => Ok(Command::ResetStats),
+            "subscribe" => Ok(Command::Subscribe),
+            "debug-touch on" => Ok(Command::DebugTouch(true)),
+            "debug-touch off" => Ok(Command::DebugTouch(false)),
+            "quiet on" => Ok(Command::Quiet(true)),
+            "quiet off" => Ok(Command::Quiet(false)),
+            "reset-brightness" => Ok(Command::SetBrightness(None)),
+            other => if let Some(level) = other.strip_prefix("set-brightness ") {
+                match level.trim().parse::<f64>() {
+                    Ok(level) if (0.0..=1.0).contains(&level) => Ok(Command::SetBrightness(Some(level))),
+                    _ => Err(anyhow!("Brightness must be between 0 and 1: {}", level)),
+                }
+            } else if let Some(flag) = other.strip_prefix("set-flag ") {
+                match flag.split_whitespace().collect::<Vec<_>>()[..] {
+                    [name, "on"] if !name.starts_with('!') => Ok(Command::SetFlag(name.to_string(), true)),
+                    [name, "off"] if !name.starts_with('!') => Ok(Command::SetFlag(name.to_string(), false)),
+                    _ => Err(anyhow!("Usage: set-flag <name> on|off")),
+                }
+            } else {
+                Err(anyhow!("Unknown command: {}", other))
+            },
+        }
+    }
+}
+
+pub struct Request {
+    pub command: Command,
+    stream: UnixStream,
+}
+
+fn send(mut stream: UnixStream, response: &str) {
+    // The stream is non-blocking, a client that does not read its reply
+    // just gets it truncated instead of stalling the main loop
+    _ = stream.write_all(response.as_bytes());
+    _ = stream.write_all(b"\n");
+}
+
+impl Request {
+    pub fn reply(self, response: &str) {
+        send(self.stream, response);
+    }
+}
+
+struct Client {
+    stream: UnixStream,
+    buf: Vec<u8>,
+}
+
+// A connection that receives one JSON line per event until it disconnects.
+// Events are queued and written without blocking, when a subscriber does not
+// keep up the newest events are dropped and the number of dropped events
+// is reported once there is room in the queue again.
+struct Subscriber {
+    stream: UnixStream,
+    queue: VecDeque<Vec<u8>>,
+    // bytes of the front of the queue that were already written
+    written: usize,
+    dropped: u64,
+    waiting_writable: bool,
+}
+
+impl Subscriber {
+    fn push(&mut self, line: String) {
+        if self.queue.len() >= MAX_QUEUED_EVENTS {
+            self.dropped += 1;
+            return;
+        }
+        if self.dropped > 0 {
+            if self.queue.len() + 2 > MAX_QUEUED_EVENTS {
+                self.dropped += 1;
+                return;
+            }
+            self.queue.push_back(format!("{}\n", json!({ "dropped": self.dropped })).into_bytes());
+            self.dropped = 0;
+        }
+        self.queue.push_back(format!("{}\n", line).into_bytes());
+    }
+    // Returns false once the subscriber is gone
+    fn flush(&mut self) -> bool {
+        while let Some(front) = self.queue.front() {
+            match self.stream.write(&front[self.written..]) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.written += n;
+                    if self.written == front.len() {
+                        self.queue.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+    fn is_closed(&mut self) -> bool {
+        let mut chunk = [0u8; MAX_REQUEST_LEN];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return true,
+                // subscribers are not expected to send anything else
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(_) => return true,
+            }
+        }
+    }
+}
+
+fn group_id(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name)?;
+    let group = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if group.is_null() {
+        return Err(anyhow!("Unknown group: {}", name));
+    }
+    Ok(unsafe { (*group).gr_gid })
+}
+
+pub struct ControlServer {
+    listener: UnixListener,
+    clients: Vec<Client>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl ControlServer {
+    // Needs to be called before dropping privileges, as /run is only writable by root.
+    // Subscribers see every press, so the socket is only open to root and the given group.
+    pub fn new(group: Option<&str>) -> Result<ControlServer> {
+        _ = fs::remove_file(SOCKET_PATH);
+        let listener = UnixListener::bind(SOCKET_PATH)?;
+        fs::set_permissions(SOCKET_PATH, Permissions::from_mode(0o660))?;
+        if let Some(group) = group {
+            match group_id(group) {
+                Ok(gid) => unix_fs::chown(SOCKET_PATH, None, Some(gid))?,
+                Err(e) => println!("Control socket is only usable by root: {}", e),
+            }
+        }
+        listener.set_nonblocking(true)?;
+        Ok(ControlServer {
+            listener,
+            clients: Vec::new(),
+            subscribers: Vec::new(),
+        })
+    }
+    // On an abstract socket of its own, for tests of what publishes presses
+    #[cfg(test)]
+    pub fn for_tests() -> ControlServer {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("tiny-dfr-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        ControlServer {
+            listener: UnixListener::bind_addr(&SocketAddr::from_abstract_name(name).unwrap()).unwrap(),
+            clients: Vec::new(),
+            subscribers: Vec::new(),
+        }
+    }
+    pub fn fd(&self) -> &impl AsFd {
+        &self.listener
+    }
+    pub fn poll(&mut self, epoll: &Epoll) -> Vec<Request> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+                    if epoll.add(&stream, EpollEvent::new(EpollFlags::EPOLLIN, 3)).is_err() {
+                        continue;
+                    }
+                    self.clients.push(Client { stream, buf: Vec::new() });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Failed to accept control connection: {}", e);
+                    break;
+                }
+            }
+        }
+        self.subscribers.retain_mut(|sub| {
+            if sub.is_closed() {
+                _ = epoll.delete(&sub.stream);
+                return false;
+            }
+            true
+        });
+        let mut requests = Vec::new();
+        let mut i = 0;
+        while i < self.clients.len() {
+            let client = &mut self.clients[i];
+            let mut chunk = [0u8; MAX_REQUEST_LEN];
+            let done = match client.stream.read(&mut chunk) {
+                Ok(0) => true,
+                Ok(n) => {
+                    client.buf.extend_from_slice(&chunk[..n]);
+                    client.buf.contains(&b'\n') || client.buf.len() >= MAX_REQUEST_LEN
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+                Err(_) => true,
+            };
+            if !done {
+                i += 1;
+                continue;
+            }
+            let client = self.clients.swap_remove(i);
+            _ = epoll.delete(&client.stream);
+            let line = String::from_utf8_lossy(&client.buf);
+            let line = line.split('\n').next().unwrap_or("");
+            match Command::parse(line) {
+                Ok(command) => requests.push(Request { command, stream: client.stream }),
+                Err(e) => send(client.stream, &json!({ "error": e.to_string() }).to_string()),
+            }
+        }
+        requests
+    }
+    pub fn subscribe(&mut self, request: Request, epoll: &Epoll) {
+        if epoll.add(&request.stream, EpollEvent::new(EpollFlags::EPOLLIN, 3)).is_err() {
+            return;
+        }
+        self.subscribers.push(Subscriber {
+            stream: request.stream,
+            queue: VecDeque::new(),
+            written: 0,
+            dropped: 0,
+            waiting_writable: false,
+        });
+    }
+    pub fn publish(&mut self, event: serde_json::Value) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let line = event.to_string();
+        for sub in &mut self.subscribers {
+            sub.push(line.clone());
+        }
+    }
+    // Writes out queued events, needs to be called before waiting for events.
+    // Subscribers with unsent events are woken up for once they become writable.
+    pub fn flush_subscribers(&mut self, epoll: &Epoll) {
+        self.subscribers.retain_mut(|sub| {
+            if !sub.flush() {
+                _ = epoll.delete(&sub.stream);
+                return false;
+            }
+            let waiting_writable = !sub.queue.is_empty();
+            if waiting_writable != sub.waiting_writable {
+                let flags = if waiting_writable {
+                    EpollFlags::EPOLLIN | EpollFlags::EPOLLOUT
+                } else {
+                    EpollFlags::EPOLLIN
+                };
+                _ = epoll.modify(&sub.stream, &mut EpollEvent::new(flags, 3));
+                sub.waiting_writable = waiting_writable;
+            }
+            true
+        });
+    }
+}
+
+pub fn run_client(args: &[String]) -> i32 {
+    let mut stream = match UnixStream::connect(SOCKET_PATH) {
+        Ok(s) => s,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            eprintln!("Failed to connect to {}: {}. Only root and the ControlGroup may use it", SOCKET_PATH, e);
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {}. Is tiny-dfr running?", SOCKET_PATH, e);
+            return 1;
+        }
+    };
+    let timeout = Some(Duration::from_millis(CLIENT_TIMEOUT_MS));
+    _ = stream.set_read_timeout(timeout);
+    _ = stream.set_write_timeout(timeout);
+    if let Err(e) = writeln!(stream, "{}", args.join(" ")) {
+        eprintln!("Failed to send command: {}", e);
+        return 1;
+    }
+    if args.len() == 1 && args[0] == "subscribe" {
+        _ = stream.set_read_timeout(None);
+        return match io::copy(&mut stream, &mut io::stdout()) {
+            Ok(_) => 0,
+            Err(e) => {
+                eprintln!("Event stream ended: {}", e);
+                1
+            }
+        };
+    }
+    let mut response = String::new();
+    if let Err(e) = stream.read_to_string(&mut response) {
+        eprintln!("Failed to read response: {}", e);
+        return 1;
+    }
+    print!("{}", response);
+    if response.starts_with("{\"error\"") { 1 } else { 0 }
+}
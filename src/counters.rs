@@ -0,0 +1,34 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+use serde_json::json;
+
+// Counts since startup for tracking reliability over long runs, reported by
+// the status command. They are only ever reset by restarting.
+pub static FRAMES: AtomicU64 = AtomicU64::new(0);
+pub static COMMITS: AtomicU64 = AtomicU64::new(0);
+pub static FAILED_COMMITS: AtomicU64 = AtomicU64::new(0);
+pub static DISPLAY_REBUILDS: AtomicU64 = AtomicU64::new(0);
+pub static FORCED_RELEASES: AtomicU64 = AtomicU64::new(0);
+pub static IGNORED_TOUCHES: AtomicU64 = AtomicU64::new(0);
+// frames that waited for FrameInterval or for the display to show the one before
+pub static DEFERRED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn to_json(started: Instant) -> serde_json::Value {
+    let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    json!({
+        "uptime_s": started.elapsed().as_secs(),
+        "frames": get(&FRAMES),
+        "commits": get(&COMMITS),
+        "failed_commits": get(&FAILED_COMMITS),
+        "display_rebuilds": get(&DISPLAY_REBUILDS),
+        "forced_releases": get(&FORCED_RELEASES),
+        "ignored_touches": get(&IGNORED_TOUCHES),
+        "deferred_frames": get(&DEFERRED_FRAMES),
+    })
+}
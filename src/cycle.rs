@@ -0,0 +1,81 @@
+use std::{
+    io::Read,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use anyhow::{Result, anyhow};
+use crate::config::CycleEntry;
+
+// The main loop waits for CycleQuery, so one that hangs is killed after this
+const QUERY_TIMEOUT_MS: u64 = 250;
+
+// A button that steps through a list of entries, each with its own label
+// and action. Every tap moves to the next entry and performs its action.
+pub struct Cycle {
+    pub entries: Vec<CycleEntry>,
+    query: Option<String>,
+    pub current: usize,
+}
+
+impl Cycle {
+    pub fn with_config(entries: Vec<CycleEntry>, query: Option<String>) -> Result<Cycle> {
+        if entries.is_empty() {
+            return Err(anyhow!("Invalid configuration, Cycle has 0 entries"));
+        }
+        Ok(Cycle { entries, query, current: 0 })
+    }
+    pub fn has_query(&self) -> bool {
+        self.query.is_some()
+    }
+    pub fn current(&self) -> &CycleEntry {
+        &self.entries[self.current]
+    }
+    // Runs CycleQuery and starts at the entry whose Text matches its output.
+    // This blocks the main loop for up to QUERY_TIMEOUT_MS.
+    pub fn query_state(&mut self) {
+        let Some(query) = &self.query else {
+            return;
+        };
+        let state = match run_query(query) {
+            Ok(state) => state,
+            Err(e) => {
+                println!("Failed to run {}: {}", query, e);
+                return;
+            }
+        };
+        match self.entries.iter().position(|entry| entry.text == state.trim()) {
+            Some(i) => self.current = i,
+            None => println!("Output of {} does not match any entry: {}", query, state.trim()),
+        }
+    }
+}
+
+fn run_query(query: &str) -> Result<String> {
+    let mut child = Command::new("sh").arg("-c").arg(query).stdin(Stdio::null()).stdout(Stdio::piped()).spawn()?;
+    let started = Instant::now();
+    while child.try_wait()?.is_none() {
+        if started.elapsed() >= Duration::from_millis(QUERY_TIMEOUT_MS) {
+            _ = child.kill();
+            _ = child.wait();
+            return Err(anyhow!("no answer within {}ms", QUERY_TIMEOUT_MS));
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    let mut state = Vec::new();
+    child.stdout.take().unwrap().read_to_end(&mut state)?;
+    Ok(String::from_utf8_lossy(&state).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_that_hangs_is_killed() {
+        let started = Instant::now();
+        assert!(run_query("sleep 10").is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(run_query("echo on").unwrap().trim(), "on");
+    }
+}
@@ -1,13 +1,13 @@
 use std::{
     fs::{File, OpenOptions, self},
-    os::unix::io::{AsFd, BorrowedFd},
-    path::Path,
+    os::unix::{fs::OpenOptionsExt, io::{AsFd, BorrowedFd}},
+    path::{Path, PathBuf},
 };
 use drm::{
-    ClientCapability, Device as DrmDevice, buffer::DrmFourcc,
+    ClientCapability, Device as DrmDevice, buffer::DrmFourcc, VblankWaitFlags, VblankWaitTarget,
     control::{
         connector, Device as ControlDevice, property, ResourceHandle, atomic, AtomicCommitFlags,
-        dumbbuffer::{DumbBuffer, DumbMapping}, framebuffer, ClipRect, Mode
+        dumbbuffer::{DumbBuffer, DumbMapping}, framebuffer, plane, ClipRect, Mode, Event
     }
 };
 use anyhow::{Result, anyhow};
@@ -23,26 +23,36 @@ impl ControlDevice for Card {}
 impl DrmDevice for Card {}
 
 impl Card {
-    fn open(path: &Path) -> Self {
+    fn open(path: &Path) -> Result<Self> {
         let mut options = OpenOptions::new();
         options.read(true);
         options.write(true);
+        // vblank events are waited for with epoll, so reading them never blocks
+        options.custom_flags(libc::O_NONBLOCK);
 
-        Card(options.open(path).unwrap())
+        Ok(Card(options.open(path)?))
     }
 }
 
 pub struct DrmBackend {
+    path: PathBuf,
     card: Card,
     mode: Mode,
+    connector: connector::Handle,
+    plane: plane::Handle,
     db: DumbBuffer,
-    fb: framebuffer::Handle
+    fb: framebuffer::Handle,
+    vblank_supported: bool,
+    // while another VT than the one of the session is shown
+    released: bool,
 }
 
 impl Drop for DrmBackend {
     fn drop(&mut self) {
-        self.card.destroy_framebuffer(self.fb).unwrap();
-        self.card.destroy_dumb_buffer(self.db).unwrap();
+        // a wedged card being replaced may refuse these, the
+        // buffers are freed with the file descriptor either way
+        _ = self.card.destroy_framebuffer(self.fb);
+        _ = self.card.destroy_dumb_buffer(self.db);
     }
 }
 
@@ -63,7 +73,7 @@ fn find_prop_id<T: ResourceHandle>(
 }
 
 fn try_open_card(path: &Path) -> Result<DrmBackend> {
-    let card = Card::open(path);
+    let card = Card::open(path)?;
     card.set_client_capability(ClientCapability::UniversalPlanes, true)?;
     card.set_client_capability(ClientCapability::Atomic, true)?;
     card.acquire_master_lock()?;
@@ -170,36 +180,134 @@ fn try_open_card(path: &Path) -> Result<DrmBackend> {
     card.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, atomic_req)?;
 
 
-    Ok(DrmBackend { card, mode, db, fb })
+    Ok(DrmBackend {
+        path: path.to_path_buf(),
+        connector: con.handle(),
+        card, mode, plane, db, fb,
+        vblank_supported: true,
+        released: false,
+    })
 }
 
 impl DrmBackend {
-    pub fn open_card() -> Result<DrmBackend> {
+    // Opens every card that drives a touchbar shaped display
+    pub fn open_cards() -> Result<Vec<DrmBackend>> {
+        let mut cards = Vec::new();
         let mut errors = Vec::new();
-        for entry in fs::read_dir("/dev/dri/")? {
-            let entry = entry?;
+        let mut entries = fs::read_dir("/dev/dri/")?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
             if !entry.file_name().to_string_lossy().starts_with("card") {
                 continue
             }
             match try_open_card(&entry.path()) {
-                Ok(card) => return Ok(card),
+                Ok(card) => cards.push(card),
                 Err(err) => {
                     errors.push(format!("{}: {}", entry.path().as_os_str().to_string_lossy(), err.to_string()))
                 }
             }
         }
+        if !cards.is_empty() {
+            return Ok(cards);
+        }
         Err(anyhow!("No touchbar device found, attempted: [\n    {}\n]", errors.join(",\n    ")))
     }
     pub fn mode(&self) -> Mode {
         self.mode
     }
+    // Where the card is in the sysfs tree, eg. below the USB device it is part of
+    pub fn sysfs_device(&self) -> Option<PathBuf> {
+        let card = self.path.file_name()?;
+        fs::canonicalize(Path::new("/sys/class/drm").join(card).join("device")).ok()
+    }
     pub fn fb_info(&self) -> Result<framebuffer::Info> {
         Ok(self.card.get_framebuffer(self.fb)?)
     }
+    // Summary of the mode and buffer that were set up, for state dumps
+    pub fn describe(&self) -> String {
+        let (width, height) = self.mode.size();
+        let mut description = format!(
+            "card: {}\nmode: {} {}x{} @ {} Hz\nformat: {:?}\n",
+            self.path.display(), self.mode.name().to_string_lossy(), width, height, self.mode.vrefresh(), DrmFourcc::Xrgb8888
+        );
+        if let Ok(info) = self.fb_info() {
+            let (fb_width, fb_height) = info.size();
+            description += &format!("framebuffer: {}x{}, pitch {}, {} bpp, depth {}\n", fb_width, fb_height, info.pitch(), info.bpp(), info.depth());
+        }
+        description
+    }
     pub fn dirty(&self, clips: &[ClipRect]) -> Result<()> {
         Ok(self.card.dirty_framebuffer(self.fb, clips)?)
     }
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.card.as_fd()
+    }
+    // Asks for an event at the next vblank, which shows that the last frame
+    // reached the display. Returns false for displays without vblank events.
+    pub fn request_vblank(&mut self) -> bool {
+        if !self.vblank_supported {
+            return false;
+        }
+        match self.card.wait_vblank(VblankWaitTarget::Relative(1), VblankWaitFlags::EVENT, 0, 0) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Vblank events are not available, not waiting for them: {}", e);
+                self.vblank_supported = false;
+                false
+            }
+        }
+    }
+    // Reads the events that arrived without waiting, returns whether there was a vblank
+    pub fn receive_vblank(&self) -> bool {
+        match self.card.receive_events() {
+            Ok(events) => events.into_iter().any(|event| matches!(event, Event::Vblank(_))),
+            Err(_) => false,
+        }
+    }
     pub fn map(&mut self) -> Result<DumbMapping> {
         Ok(self.card.map_dumb_buffer(&mut self.db)?)
     }
+    // Checks that the connector is still there and that the kernel would
+    // still accept showing our framebuffer, without changing anything
+    pub fn check(&self) -> Result<()> {
+        let con = self.card.get_connector(self.connector, false)?;
+        if con.state() != connector::State::Connected {
+            return Err(anyhow!("Connector is no longer connected"));
+        }
+        let mut atomic_req = atomic::AtomicModeReq::new();
+        atomic_req.add_property(
+            self.plane,
+            find_prop_id(&self.card, self.plane, "FB_ID")?,
+            property::Value::Framebuffer(Some(self.fb)),
+        );
+        self.card.atomic_commit(AtomicCommitFlags::TEST_ONLY, atomic_req)?;
+        Ok(())
+    }
+    // Lets another program show something on the display, frames are dropped meanwhile
+    pub fn release_master(&mut self) -> Result<()> {
+        self.released = true;
+        Ok(self.card.release_master_lock()?)
+    }
+    pub fn acquire_master(&mut self) -> Result<()> {
+        self.card.acquire_master_lock()?;
+        self.released = false;
+        Ok(())
+    }
+    pub fn is_released(&self) -> bool {
+        self.released
+    }
+    // Sets up the card from scratch, the old buffers are freed once the new ones are shown
+    pub fn reinit(&mut self) -> Result<()> {
+        _ = self.card.release_master_lock();
+        match try_open_card(&self.path) {
+            Ok(backend) => {
+                *self = backend;
+                Ok(())
+            }
+            Err(e) => {
+                _ = self.card.acquire_master_lock();
+                Err(e)
+            }
+        }
+    }
 }
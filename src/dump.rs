@@ -0,0 +1,45 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use anyhow::Result;
+use cairo::{Format, ImageSurface};
+
+// Everything that goes into a state dump, copied out on the main loop
+// so that the files can be written on another thread
+pub struct StateDump {
+    pub frame: Vec<u8>,
+    pub width: i32,
+    pub height: i32,
+    pub stride: i32,
+    pub config: serde_json::Value,
+    pub display: String,
+    pub events: String,
+}
+
+fn write_files(dir: &Path, dump: StateDump) -> Result<()> {
+    // the frame is stored the way it is sent to the display, rotated by 90 degrees
+    let frame = ImageSurface::create_for_data(dump.frame, Format::ARgb32, dump.width, dump.height, dump.stride)?;
+    frame.write_to_png(&mut File::create(dir.join("frame.png"))?)?;
+    fs::write(dir.join("config.json"), serde_json::to_string_pretty(&dump.config)?)?;
+    fs::write(dir.join("display.txt"), dump.display)?;
+    fs::write(dir.join("events.txt"), dump.events)?;
+    Ok(())
+}
+
+// The directory is created right away so that its path can be shown,
+// encoding and writing the files happens in the background
+pub fn write(dump: StateDump) -> Result<PathBuf> {
+    let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let dir = PathBuf::from(format!("/tmp/tiny-dfr-{}", time_ms));
+    fs::create_dir(&dir)?;
+    let path = dir.clone();
+    thread::spawn(move || {
+        if let Err(e) = write_files(&path, dump) {
+            println!("Failed to write state dump to {}: {}", path.display(), e);
+        }
+    });
+    Ok(dir)
+}
@@ -0,0 +1,73 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    time::Instant,
+};
+
+const CAPACITY: usize = 256;
+
+// Kept small and Copy, so that recording never allocates
+#[derive(Clone, Copy)]
+pub enum Record {
+    TouchDown { bar: usize, slot: u32, layer: usize, button: Option<usize> },
+    TouchUp { bar: usize, slot: u32 },
+    LayerShown { bar: usize, layer: usize },
+    FrameShown { bar: usize, clips: usize },
+    FrameFailed { bar: usize },
+    DisplayRebuilt { bar: usize },
+    Error(&'static str),
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Record::TouchDown { bar, slot, layer, button } => match button {
+                Some(button) => write!(f, "bar {}: touch {} down on layer {} button {}", bar, slot, layer, button),
+                None => write!(f, "bar {}: touch {} down on layer {} outside of the buttons", bar, slot, layer),
+            },
+            Record::TouchUp { bar, slot } => write!(f, "bar {}: touch {} up", bar, slot),
+            Record::LayerShown { bar, layer } => write!(f, "bar {}: showing layer {}", bar, layer),
+            Record::FrameShown { bar, clips } => write!(f, "bar {}: frame shown, {} regions", bar, clips),
+            Record::FrameFailed { bar } => write!(f, "bar {}: frame failed", bar),
+            Record::DisplayRebuilt { bar } => write!(f, "bar {}: display set up again", bar),
+            Record::Error(message) => write!(f, "error: {}", message),
+        }
+    }
+}
+
+// The last CAPACITY significant events, written out on a panic and by
+// DumpState to show what led up to a problem
+struct EventLog {
+    entries: [Option<(Instant, Record)>; CAPACITY],
+    next: usize,
+}
+
+static LOG: Mutex<EventLog> = Mutex::new(EventLog {
+    entries: [None; CAPACITY],
+    next: 0,
+});
+
+pub fn record(record: Record) {
+    let Ok(mut log) = LOG.lock() else {
+        return;
+    };
+    let next = log.next;
+    log.entries[next] = Some((Instant::now(), record));
+    log.next = (next + 1) % CAPACITY;
+}
+
+// Oldest event first, with times relative to now
+pub fn format() -> String {
+    // try_lock, as this also runs in the panic hook, which may have
+    // interrupted a thread in the middle of recording
+    let Ok(log) = LOG.try_lock() else {
+        return "event log unavailable\n".to_string();
+    };
+    let mut out = String::new();
+    for i in 0..CAPACITY {
+        if let Some((at, record)) = &log.entries[(log.next + i) % CAPACITY] {
+            out += &format!("-{:>9.3}s {}\n", at.elapsed().as_secs_f64(), record);
+        }
+    }
+    out
+}
@@ -0,0 +1,32 @@
+use std::{
+    collections::BTreeSet,
+    sync::Mutex,
+};
+
+// Named flags set over the control socket, eg. by a script watching whether
+// a VPN is connected. Buttons with VisibleWhen are only shown while theirs is set.
+// They are kept across config reloads, but start out unset after a restart.
+static FLAGS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// Returns whether the flag changed
+pub fn set(name: &str, value: bool) -> bool {
+    let mut flags = FLAGS.lock().unwrap();
+    if value {
+        flags.insert(name.to_string())
+    } else {
+        flags.remove(name)
+    }
+}
+
+// A condition is the name of a flag, or the name with a leading ! for when it is not set
+pub fn check(condition: &str) -> bool {
+    let flags = FLAGS.lock().unwrap();
+    match condition.strip_prefix('!') {
+        Some(name) => !flags.contains(name),
+        None => flags.contains(condition),
+    }
+}
+
+pub fn to_json() -> serde_json::Value {
+    FLAGS.lock().unwrap().iter().cloned().collect()
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    fs,
+    time::Instant,
+};
+
+const POLL_INTERVAL_MS: i32 = 250;
+
+// libinput does not report keyboard LED changes, the compositor sets them
+// directly on the device, so the state is read back from the LED class
+// devices the input subsystem registers (eg. input3::capslock).
+fn led_is_on(name: &str) -> bool {
+    let suffix = format!("::{}", name);
+    let Ok(entries) = fs::read_dir("/sys/class/leds/") else {
+        return false;
+    };
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().ends_with(&suffix) {
+            continue;
+        }
+        let on = fs::read_to_string(entry.path().join("brightness"))
+            .map(|b| b.trim() != "0")
+            .unwrap_or(false);
+        if on {
+            return true;
+        }
+    }
+    false
+}
+
+pub struct LedManager {
+    last_poll: Instant,
+    poll_requested: bool,
+    indicators: Vec<bool>,
+}
+
+impl LedManager {
+    pub fn new() -> LedManager {
+        LedManager {
+            last_poll: Instant::now(),
+            poll_requested: true,
+            indicators: Vec::new(),
+        }
+    }
+    // Lock keys are handled by the compositor, so the LEDs usually change
+    // right after a key press on the main keyboard
+    pub fn request_poll(&mut self) {
+        self.poll_requested = true;
+    }
+    // Returns whether the indicators changed and when to poll next
+    pub fn update(&mut self, mapping: &HashMap<String, usize>) -> (bool, i32) {
+        if mapping.is_empty() {
+            let changed = !self.indicators.is_empty();
+            self.indicators.clear();
+            return (changed, i32::MAX);
+        }
+        let since_last_poll = self.last_poll.elapsed().as_millis() as i32;
+        if since_last_poll < POLL_INTERVAL_MS && !self.poll_requested {
+            return (false, POLL_INTERVAL_MS - since_last_poll);
+        }
+        self.last_poll = Instant::now();
+        self.poll_requested = false;
+        let len = mapping.values().max().unwrap() + 1;
+        let mut indicators = vec![false; len];
+        for (name, &button) in mapping {
+            indicators[button] |= led_is_on(name);
+        }
+        let changed = indicators != self.indicators;
+        self.indicators = indicators;
+        (changed, POLL_INTERVAL_MS)
+    }
+    pub fn indicators(&self) -> &[bool] {
+        &self.indicators
+    }
+}
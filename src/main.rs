@@ -1,21 +1,24 @@
 use std::{
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     os::{
         fd::{AsRawFd, AsFd},
         unix::{io::OwnedFd, fs::OpenOptionsExt}
     },
-    path::Path,
-    collections::HashMap,
+    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     cmp::min,
     panic::{self, AssertUnwindSafe},
-    time::Instant,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex, PoisonError, atomic::{AtomicBool, Ordering}},
+    env, mem,
+    process::{self, Stdio},
+    thread::{self, Scope},
 };
-use cairo::{ImageSurface, Format, Context, Surface, Rectangle, Antialias};
-use rsvg::{Loader, CairoRenderer, SvgHandle};
 use drm::control::ClipRect;
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use input::{
-    Libinput, LibinputInterface, Device as InputDevice,
+    Libinput, LibinputInterface, Device as InputDevice, DeviceCapability,
     event::{
         Event, device::DeviceEvent, EventTrait,
         touch::{TouchEvent, TouchEventPosition, TouchEventSlot},
@@ -23,11 +26,12 @@ use input::{
     }
 };
 use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY, c_char};
-use input_linux::{uinput::UInputHandle, EventKind, Key, SynchronizeKind};
+use input_linux::{uinput::UInputHandle, EventKind, Key, RelativeAxis, SynchronizeKind};
 use input_linux_sys::{uinput_setup, input_id, timeval, input_event};
 use nix::{
     sys::{
         signal::{Signal, SigSet},
+        signalfd::{SignalFd, SfdFlags},
         epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags}
     }, 
     errno::Errno
@@ -39,134 +43,336 @@ mod display;
 mod pixel_shift;
 mod fonts;
 mod config;
+mod control;
+mod stats;
+mod text_input;
+mod leds;
+mod slider;
+mod watchdog;
+mod modifiers;
+mod dump;
+mod event_log;
+mod cycle;
+mod counters;
+mod present;
+mod render;
+mod flags;
+mod vt;
+mod state;
 
 use backlight::BacklightManager;
 use display::DrmBackend;
 use pixel_shift::{PixelShiftManager, PIXEL_SHIFT_WIDTH_PX};
-use config::{ButtonConfig, Config};
+use config::{Action, ButtonConfig, Config, ExtraTouches, Feedback, IndicatorPosition, MissingUinput, VtSwitch};
 use crate::config::ConfigManager;
+use control::{Command, ControlServer};
+use stats::UsageStats;
+use state::{BarState, SavedState, StateFile};
+use leds::LedManager;
+use slider::Slider;
+use cycle::Cycle;
+use watchdog::DisplayWatchdog;
+use modifiers::{Modifiers, ModifierTracker};
+use dump::StateDump;
+use event_log::Record;
+use counters::{DEFERRED_FRAMES, DISPLAY_REBUILDS, FORCED_RELEASES, IGNORED_TOUCHES};
+use render::{ButtonScene, Failure, IconSource, Image, Renderer, Scene, Snapshot, Style};
+use vt::VtWatcher;
+use serde_json::json;
 
 const BUTTON_SPACING_PX: i32 = 16;
-const ICON_SIZE: i32 = 48;
+const GROUP_SPACING_PX: i32 = 2;
 
 const TIMEOUT_MS: i32 = 10 * 1000;
 const MAX_FPS: f64 = 30.;
+// how long a touch waits for a second finger before pressing the button, with TwoFingerPeek
+const PEEK_WINDOW_MS: i32 = 80;
+const CONFIRM_TIMEOUT_MS: i32 = 3000;
+const CONFIRM_LABEL: &str = "Sure?";
+const UINPUT_WRITE_ATTEMPTS: usize = 3;
+const UINPUT_RECREATE_INTERVAL_MS: i32 = 5000;
+const UINPUT_OPEN_MAX_DELAY_MS: u64 = 5000;
+const TOAST_MS: i32 = 3000;
+// how long and how many touches are kept while the digitizer is not set up yet
+const EARLY_TOUCH_WINDOW_MS: u128 = 500;
+const MAX_EARLY_TOUCHES: usize = 64;
 
-enum ButtonImage {
-    Text(String),
-    Svg(SvgHandle),
-    Bitmap(ImageSurface)
-}
+static UINPUT_FAILED: AtomicBool = AtomicBool::new(false);
+// set by Quiet buttons and the quiet command, the main loop applies changes to it
+static QUIET: AtomicBool = AtomicBool::new(false);
+// set when something StateFile keeps changes, a Cycle entry, a layer picked
+// with a Layer action or quiet mode, the main loop saves it then
+static STATE_CHANGED: AtomicBool = AtomicBool::new(false);
+
+const KEYPAD_KEYS: [Key; 17] = [
+    Key::Kp0, Key::Kp1, Key::Kp2, Key::Kp3, Key::Kp4, Key::Kp5, Key::Kp6, Key::Kp7,
+    Key::Kp8, Key::Kp9, Key::KpDot, Key::KpPlus, Key::KpMinus, Key::KpAsterisk,
+    Key::KpSlash, Key::KpEnter, Key::KpEqual,
+];
 
 struct Button {
-    image: ButtonImage,
+    image: Image,
+    label: String,
     changed: bool,
     active: bool,
-    action: Key,
+    action: Action,
+    slider: Option<Slider>,
+    group: Option<String>,
+    repeat: Option<bool>,
+    tap_dead_time_ms: Option<u32>,
+    // when the last tap on the button ended
+    released_at: Option<Instant>,
+    confirm: bool,
+    armed_at: Option<Instant>,
+    // the press was confirmed, so the release needs to be sent as well
+    confirmed: bool,
+    shift_action: Option<Action>,
+    ctrl_action: Option<Action>,
+    alt_action: Option<Action>,
+    two_finger_action: Option<Action>,
+    // set while the button is pressed by two fingers at once, to pick two_finger_action
+    two_finger_press: bool,
+    // the action picked when the button was pressed, which is also
+    // released even if the modifiers changed in the meantime
+    pressed_action: Option<Action>,
+    cycle: Option<Cycle>,
+    // icons are drawn in this color, using only their transparency
+    icon_color: Option<(f64, f64, f64)>,
+    // whether the button lights up while pressed
+    flash: bool,
+    // the flag condition the button is shown under, see flags::check
+    visible_when: Option<String>,
 
     last_action: (f64, Instant), // value when action was performed, when
     last_rendered_level: f64,
-}
-
-fn try_load_svg(path: &str) -> Result<ButtonImage> {
-    let handle = Loader::new().read_path(format!("/etc/tiny-dfr/{}.svg", path)).or_else(|_| {
-        Loader::new().read_path(format!("/usr/share/tiny-dfr/{}.svg", path))
-    })?;
-    Ok(ButtonImage::Svg(handle))
-}
-
-fn try_load_png(path: &str) -> Result<ButtonImage> {
-    let mut file = File::open(format!("/etc/tiny-dfr/{}.png", path)).or_else(|_| {
-        File::open(format!("/usr/share/tiny-dfr/{}.png", path))
-    })?;
-    let surf = ImageSurface::create_from_png(&mut file)?;
-    if surf.height() == ICON_SIZE && surf.width() == ICON_SIZE {
-        return Ok(ButtonImage::Bitmap(surf));
-    }
-    let resized = ImageSurface::create(Format::ARgb32, ICON_SIZE, ICON_SIZE).unwrap();
-    let c = Context::new(&resized).unwrap();
-    c.scale(ICON_SIZE as f64 / surf.width() as f64, ICON_SIZE as f64 / surf.height() as f64);
-    c.set_source_surface(surf, 0.0, 0.0).unwrap();
-    c.set_antialias(Antialias::Best);
-    c.paint().unwrap();
-    return Ok(ButtonImage::Bitmap(resized));
+    last_rendered_progress: f64,
 }
 
 impl Button {
-    fn with_config(cfg: ButtonConfig) -> Button {
-        if let Some(text) = cfg.text {
-            Button::new_text(text, cfg.action)
-        } else if let Some(icon) = cfg.icon {
-            Button::new_icon(&icon, cfg.action)
+    fn with_config(cfg: ButtonConfig, layer_feedback: Feedback) -> Result<Button> {
+        let mut button = if let Some(entries) = cfg.cycle {
+            // the label comes from the entries
+            let cycle = Cycle::with_config(entries, cfg.cycle_query)?;
+            let entry = cycle.current().clone();
+            let mut button = Button::new_text(entry.text, entry.action);
+            button.cycle = Some(cycle);
+            button
         } else {
-            panic!("Invalid config, a button must have either Text or Icon")
-        }
+            let action = cfg.action.ok_or(anyhow!("Invalid config, a button must have an Action or Cycle"))?;
+            if let Some(text) = cfg.text {
+                Button::new_text(text, action)
+            } else if let Some(icon) = cfg.icon {
+                Button::new_icon(&icon, action)?
+            } else {
+                return Err(anyhow!("Invalid config, a button must have either Text or Icon"))
+            }
+        };
+        button.slider = cfg.slider.as_ref().map(Slider::with_config);
+        button.group = cfg.group;
+        button.repeat = cfg.repeat;
+        button.tap_dead_time_ms = cfg.tap_dead_time;
+        button.confirm = cfg.confirm.unwrap_or(false);
+        button.shift_action = cfg.shift_action;
+        button.ctrl_action = cfg.ctrl_action;
+        button.alt_action = cfg.alt_action;
+        button.two_finger_action = cfg.two_finger_action;
+        button.icon_color = cfg.icon_color;
+        button.visible_when = cfg.visible_when;
+        button.flash = cfg.feedback.unwrap_or_default().or(layer_feedback).flash.unwrap_or(true);
+        Ok(button)
     }
-    fn new_text(text: String, action: Key) -> Button {
+    fn new_text(text: String, action: Action) -> Button {
         Button {
             action,
+            label: text.clone(),
+            slider: None,
+            group: None,
+            repeat: None,
+            tap_dead_time_ms: None,
+            released_at: None,
+            confirm: false,
+            armed_at: None,
+            confirmed: false,
+            shift_action: None,
+            ctrl_action: None,
+            alt_action: None,
+            two_finger_action: None,
+            two_finger_press: false,
+            pressed_action: None,
+            cycle: None,
+            icon_color: None,
+            flash: true,
+            visible_when: None,
             active: false,
             changed: false,
             last_action: (0., Instant::now()),
             last_rendered_level: 0.,
-            image: ButtonImage::Text(text)
+            last_rendered_progress: 0.,
+            image: Image::Text(text)
         }
     }
-    fn new_icon(path: &str, action: Key) -> Button {
-        let image = try_load_svg(path).or_else(|_| try_load_png(path)).unwrap();
-        Button {
+    fn new_icon(path: &str, action: Action) -> Result<Button> {
+        let image = IconSource::find(path)
+            .map(|source| Image::Icon(Arc::new(source)))
+            .map_err(|e| anyhow!("Failed to load icon {}: {}", path, e))?;
+        Ok(Button {
             action, image,
+            label: path.to_string(),
+            slider: None,
+            group: None,
+            repeat: None,
+            tap_dead_time_ms: None,
+            released_at: None,
+            confirm: false,
+            armed_at: None,
+            confirmed: false,
+            shift_action: None,
+            ctrl_action: None,
+            alt_action: None,
+            two_finger_action: None,
+            two_finger_press: false,
+            pressed_action: None,
+            cycle: None,
+            icon_color: None,
+            flash: true,
+            visible_when: None,
             active: false,
             changed: false,
             last_rendered_level: 0.,
+            last_rendered_progress: 0.,
             last_action: (0., Instant::now()),
-        }
+        })
     }
-    fn render(&mut self, config: &Config, c: &Context, height: i32, button_left_edge: f64, button_width: u64, y_shift: f64) {
-        let y_shift = y_shift - self.get_level(config) * config.button_style.bounce;
-        self.last_rendered_level = self.get_level(config);
+    fn visible(&self) -> bool {
+        self.visible_when.as_deref().map_or(true, flags::check)
+    }
+    fn set_active<W>(&mut self, config: &Config, uinput: &mut W, modifiers: Modifiers, active: bool) -> bool where W: EventSink {
+        if self.active == active {
+            return false;
+        }
+        self.last_action = (self.get_level(config), Instant::now());
 
-        match &self.image {
-            ButtonImage::Text(text) => {
-                let extents = c.text_extents(text).unwrap();
-                c.move_to(
-                    button_left_edge + (button_width as f64 / 2.0 - extents.width() / 2.0).round(),
-                    y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round()
-                );
-                c.show_text(text).unwrap();
-            },
-            ButtonImage::Svg(svg) => {
-                let renderer = CairoRenderer::new(&svg);
-                let x = button_left_edge + (button_width as f64 / 2.0 - (ICON_SIZE / 2) as f64).round();
-                let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
+        self.active = active;
+        self.changed = true;
+        if !active {
+            self.released_at = Some(Instant::now());
+        }
 
-                renderer.render_document(c,
-                    &Rectangle::new(x, y, ICON_SIZE as f64, ICON_SIZE as f64)
-                ).unwrap();
+        let action = if active {
+            // a tap moves a Cycle button on to its next entry, and performs that.
+            // With Confirm only the confirming tap does, the first one just arms it.
+            let confirmed = !self.confirm || self.slider.is_some() || self.armed_at.is_some();
+            if let Some(cycle) = self.cycle.as_ref().filter(|_| confirmed) {
+                self.show_cycle_entry((cycle.current + 1) % cycle.entries.len());
             }
-            ButtonImage::Bitmap(surf) => {
-                let x = button_left_edge + (button_width as f64 / 2.0 - (ICON_SIZE / 2) as f64).round();
-                let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
-                c.set_source_surface(surf, x, y).unwrap();
-                c.rectangle(x, y, ICON_SIZE as f64, ICON_SIZE as f64);
-                c.fill().unwrap();
+            let action = self.action_for(modifiers).clone();
+            // what the touchbar handles itself, see Bar::press, waits for the confirming tap too
+            if confirmed {
+                self.pressed_action = Some(action.clone());
+            }
+            action
+        } else {
+            self.pressed_action.take().unwrap_or_else(|| self.action.clone())
+        };
+        // sliders decide what to emit based on the motion of the touch
+        if self.slider.is_none() && self.confirm {
+            self.confirm_action(uinput, &action, active);
+        } else if self.slider.is_none() {
+            match action {
+                // released right away, so that holding the button does not let the key repeat
+                Action::Key(key) if !self.repeats(config, key) => if active {
+                    toggle_key(uinput, key, 1);
+                    toggle_key(uinput, key, 0);
+                },
+                _ => perform_action(uinput, &action, active),
             }
         }
+        true
+    }
+
+    // Ctrl takes precedence over Alt, which takes precedence over Shift
+    fn action_for(&self, modifiers: Modifiers) -> &Action {
+        if let Some(action) = self.two_finger_action.as_ref().filter(|_| self.two_finger_press) {
+            return action;
+        }
+        let modified = [
+            (modifiers.ctrl, &self.ctrl_action),
+            (modifiers.alt, &self.alt_action),
+            (modifiers.shift, &self.shift_action),
+        ];
+        modified.into_iter()
+            .find_map(|(held, action)| action.as_ref().filter(|_| held))
+            .unwrap_or(&self.action)
+    }
+
+    fn actions(&self) -> impl Iterator<Item = &Action> {
+        [&self.shift_action, &self.ctrl_action, &self.alt_action, &self.two_finger_action].into_iter()
+            .flatten()
+            .chain([&self.action])
+            .chain(self.cycle.iter().flat_map(|cycle| cycle.entries.iter().map(|entry| &entry.action)))
+    }
+
+    fn show_cycle_entry(&mut self, idx: usize) {
+        let Some(cycle) = &mut self.cycle else {
+            return;
+        };
+        cycle.current = idx;
+        STATE_CHANGED.store(true, Ordering::Relaxed);
+        let entry = cycle.current().clone();
+        self.action = entry.action;
+        self.label = entry.text.clone();
+        self.image = Image::Text(entry.text);
+        self.changed = true;
+    }
+
+    // The first press arms the button, a second one while armed performs the action
+    fn confirm_action<W>(&mut self, uinput: &mut W, action: &Action, active: bool) where W: EventSink {
+        if active && self.armed_at.is_some() {
+            self.armed_at = None;
+            self.confirmed = true;
+            perform_action(uinput, action, true);
+        } else if active {
+            self.armed_at = Some(Instant::now());
+        } else if self.confirmed {
+            self.confirmed = false;
+            perform_action(uinput, action, false);
+        }
     }
-    fn set_active<F>(&mut self, config: &Config, uinput: &mut UInputHandle<F>, active: bool) where F: AsRawFd {
-        if self.active != active {
-            self.last_action = (self.get_level(config), Instant::now());
 
-            self.active = active;
+    fn disarm(&mut self) {
+        if self.armed_at.take().is_some() {
             self.changed = true;
+        }
+    }
 
-            toggle_key(uinput, self.action, active as i32);
+    // Returns when the button needs to be disarmed
+    fn expire_confirm(&mut self) -> i32 {
+        let Some(armed_at) = self.armed_at else {
+            return i32::MAX;
+        };
+        let armed_for = armed_at.elapsed().as_millis() as i32;
+        if armed_for >= CONFIRM_TIMEOUT_MS {
+            self.disarm();
+            return i32::MAX;
         }
+        CONFIRM_TIMEOUT_MS - armed_for
+    }
+
+    // A touch this soon after the last tap ended is most likely a bounce
+    fn in_dead_time(&self, config: &Config) -> bool {
+        let dead_time_ms = self.tap_dead_time_ms.unwrap_or(config.tap_dead_time_ms);
+        self.released_at.map_or(false, |at| at.elapsed().as_millis() < dead_time_ms as u128)
+    }
+
+    fn repeats(&self, config: &Config, key: Key) -> bool {
+        self.repeat.unwrap_or_else(|| {
+            config.repeat_keys.as_ref().map_or(true, |keys| keys.contains(&key))
+        })
     }
 
     // flash level from 0 to 1. scales color
     fn get_level(&self, conf: &Config) -> f64 {
-        let wanted = if self.active { 1. } else { 0. };
+        let wanted = self.wanted_level();
         let current = self.last_action.0;
 
         let elapsed = self.last_action.1.elapsed().as_secs_f64();
@@ -175,8 +381,14 @@ impl Button {
         brightness
     }
 
-    fn get_color(&self, conf: &Config) -> (f64, f64, f64) {
-        let (ir, ig, ib) = conf.button_style.inactive_color;
+    fn get_color(&self, conf: &Config, indicated: bool) -> (f64, f64, f64) {
+        let (ir, ig, ib) = if self.armed_at.is_some() {
+            conf.button_style.warning_color
+        } else if indicated {
+            conf.button_style.indicator_color
+        } else {
+            conf.button_style.inactive_color
+        };
         let (ar, ag, ab) = conf.button_style.active_color;
         let i = self.get_level(conf);
         let r = ir + (i * (ar - ir));
@@ -185,112 +397,197 @@ impl Button {
         (r, g, b)
     }
 
+    fn wanted_level(&self) -> f64 {
+        if self.active && self.flash { 1. } else { 0. }
+    }
+
     fn needs_redraw(&self, config: &Config) -> bool {
-        let close = (self.last_rendered_level - (if self.active { 1. } else { 0. })).abs() < (1. / 256.0);
-        self.changed || !close
+        let close = (self.last_rendered_level - self.wanted_level()).abs() < (1. / 256.0);
+        self.changed || !close || self.hold_progress(config).unwrap_or(0.0) != self.last_rendered_progress
+    }
+    // How far a hold got towards showing the preview of what the button does,
+    // from 0 to 1, while HoldProgressLine is drawn for it
+    fn hold_progress(&self, config: &Config) -> Option<f64> {
+        if !config.hold_progress_line || config.hold_preview_ms == 0 || QUIET.load(Ordering::Relaxed) {
+            return None;
+        }
+        if !self.active || matches!(self.image, Image::Text(_)) {
+            return None;
+        }
+        let held_ms = self.last_action.1.elapsed().as_millis() as f64;
+        Some((held_ms / config.hold_preview_ms as f64).min(1.0))
     }
 }
 
+struct Toast {
+    text: String,
+    shown_at: Instant,
+    // the held button this describes, shown until it is released
+    preview_of: Option<(usize, usize)>,
+}
+
+// Mirrors a touch the same way as the drawing, see transform_to_bar
+fn flip_touch(config: &Config, width: u16, height: u16, x: f64, y: f64) -> (f64, f64) {
+    let x = if config.flip_horizontal { width as f64 - x } else { x };
+    let y = if config.flip_vertical { height as f64 - y } else { y };
+    (x, y)
+}
+
 #[derive(Default)]
 pub struct FunctionLayer {
+    name: String,
     buttons: Vec<Button>
 }
 
 impl FunctionLayer {
-    fn with_config(cfg: Vec<ButtonConfig>) -> FunctionLayer {
+    fn with_config(name: &str, cfg: Vec<ButtonConfig>, feedback: Feedback) -> Result<FunctionLayer> {
         if cfg.is_empty() {
-            panic!("Invalid configuration, layer has 0 buttons");
-        }
-        FunctionLayer {
-            buttons: cfg.into_iter().map(Button::with_config).collect()
+            return Err(anyhow!("Invalid configuration, layer has 0 buttons"));
         }
+        Ok(FunctionLayer {
+            name: name.to_string(),
+            buttons: cfg.into_iter().map(|button| Button::with_config(button, feedback)).collect::<Result<_>>()?
+        })
     }
-    fn draw(&mut self, config: &Config, width: i32, height: i32, surface: &Surface, pixel_shift: (f64, f64), complete_redraw: bool) -> Vec<ClipRect> {
-        let c = Context::new(&surface).unwrap();
-        let mut modified_regions = if complete_redraw {
-            vec![ClipRect::new(0, 0, height as u16, width as u16)]
+    fn set_active<W>(&mut self, idx: usize, config: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer, modifiers: Modifiers, active: bool) where W: EventSink {
+        let button = &mut self.buttons[idx];
+        let pressed_at = button.last_action.1;
+        if !button.set_active(config, uinput, modifiers, active) {
+            return;
+        }
+        if active {
+            stats.record_press(&self.name, &button.label);
         } else {
-            Vec::new()
-        };
-        c.translate(height as f64, 0.0);
-        c.rotate((90.0f64).to_radians());
+            stats.record_release(&self.name, &button.label, pressed_at.elapsed());
+        }
+        let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        control.publish(json!({
+            "time_ms": time_ms,
+            "layer": self.name,
+            "button": idx,
+            "label": button.label,
+            "action": button.action.kind(),
+            "state": if active { "press" } else { "release" },
+        }));
+    }
+    fn disarm(&mut self) {
+        for button in &mut self.buttons {
+            button.disarm();
+        }
+    }
+    fn in_group_with(&self, a: usize, b: usize) -> bool {
+        self.buttons[a].group.is_some() && self.buttons[a].group == self.buttons[b].group
+    }
+    // whether the corners between two neighbouring buttons are square
+    fn joined(&self, config: &Config, a: usize, b: usize) -> bool {
+        config.edge_to_edge || self.in_group_with(a, b)
+    }
+    // Returns the left edge and width of every button when laid out in the given width.
+    // Consecutive buttons of the same group are only separated by a thin gap, which
+    // together with their inner corners not being rounded makes them look like one
+    // box with dividers. With EdgeToEdge there are no gaps at all and the buttons
+    // tile the whole width, the dividers are only drawn over the seams.
+    // Hidden buttons take up no space and get a width of 0.
+    fn layout(&self, config: &Config, width: f64) -> Vec<(f64, f64)> {
+        let indicator_width = render::layer_indicator_width(config.layer_indicator);
+        let width = width - indicator_width;
+        let visible: Vec<usize> = (0..self.buttons.len()).filter(|&i| self.buttons[i].visible()).collect();
+        let gaps: Vec<f64> = visible.windows(2).map(|pair| {
+            if config.edge_to_edge {
+                0.0
+            } else if self.in_group_with(pair[0], pair[1]) {
+                GROUP_SPACING_PX as f64
+            } else {
+                BUTTON_SPACING_PX as f64
+            }
+        }).collect();
+        let button_width = (width - gaps.iter().sum::<f64>()) / visible.len().max(1) as f64;
+        let mut left_edge = if config.layer_indicator_position == IndicatorPosition::Left { indicator_width } else { 0.0 };
+        let mut layout = vec![(left_edge, 0.0); self.buttons.len()];
+        for (n, &i) in visible.iter().enumerate() {
+            if n > 0 {
+                left_edge += button_width + gaps[n - 1];
+            }
+            layout[i] = (left_edge, button_width);
+        }
+        layout
+    }
+    // the closest buttons on both sides of the given one that are shown
+    fn visible_neighbours(&self, idx: usize) -> (Option<usize>, Option<usize>) {
+        let before = (0..idx).rev().find(|&i| self.buttons[i].visible());
+        let after = (idx + 1..self.buttons.len()).find(|&i| self.buttons[i].visible());
+        (before, after)
+    }
+    // Returns the first pixel of every button as it is drawn, and the one after its
+    // last, with the pixel shift at its center. Touches hit the same pixels, so
+    // a finger on the seam between two buttons that touch is on exactly one.
+    fn edges(&self, config: &Config, width: u16) -> Vec<(f64, f64)> {
         let pixel_shift_width = if config.enable_pixel_shift { PIXEL_SHIFT_WIDTH_PX } else { 0 };
-        let button_width = ((width - pixel_shift_width as i32) - (BUTTON_SPACING_PX * (self.buttons.len() - 1) as i32)) as f64 / self.buttons.len() as f64;
-        let radius = 8.0f64;
-        let bot = (height as f64) * 0.15;
-        let top = (height as f64) * 0.85;
-        let (pixel_shift_x, pixel_shift_y) = pixel_shift;
-
-        if complete_redraw {
-            c.set_source_rgb(0.0, 0.0, 0.0);
-            c.paint().unwrap();
-        }
-        c.set_font_face(&config.font_face);
-        c.set_font_size(32.0);
-        for (i, button) in self.buttons.iter_mut().enumerate() {
-            if !button.needs_redraw(config) && !complete_redraw {
-                continue;
+        let layout = self.layout(config, (width as i32 - pixel_shift_width as i32) as f64);
+        let offset = (pixel_shift_width / 2) as f64;
+        (0..self.buttons.len()).map(|i| {
+            let (left_edge, button_width) = layout[i];
+            // buttons that touch end where the next one starts
+            let right_edge = match self.visible_neighbours(i).1.map(|next| layout[next]) {
+                Some((next_left, _)) if config.edge_to_edge => next_left.floor(),
+                _ => left_edge.floor() + button_width.ceil(),
             };
-
-            let left_edge = (i as f64 * (button_width + BUTTON_SPACING_PX as f64)).floor() + pixel_shift_x + (pixel_shift_width / 2) as f64;
-            let color = button.get_color(config);
-            if !complete_redraw {
-                c.set_source_rgb(0.0, 0.0, 0.0);
-                c.rectangle(left_edge, bot - radius, button_width, top - bot + radius * 2.0);
-                c.fill().unwrap();
-            }
-            c.set_source_rgb(color.0, color.1, color.2);
-            // draw box with rounded corners
-            c.new_sub_path();
-            let left = left_edge + radius;
-            let right = (left_edge + button_width.ceil()) - radius;
-            c.arc(
-                right,
-                bot,
-                radius,
-                (-90.0f64).to_radians(),
-                (0.0f64).to_radians(),
-            );
-            c.arc(
-                right,
-                top,
-                radius,
-                (0.0f64).to_radians(),
-                (90.0f64).to_radians(),
-            );
-            c.arc(
-                left,
-                top,
-                radius,
-                (90.0f64).to_radians(),
-                (180.0f64).to_radians(),
-            );
-            c.arc(
-                left,
-                bot,
-                radius,
-                (180.0f64).to_radians(),
-                (270.0f64).to_radians(),
-            );
-            c.close_path();
-
-            c.fill().unwrap();
-            c.set_source_rgb(1.0, 1.0, 1.0);
-            button.render(config, &c, height, left_edge, button_width.ceil() as u64, pixel_shift_y);
-
-            button.changed = false;
-
-            if !complete_redraw {
-                modified_regions.push(ClipRect::new(
-                    height as u16 - top as u16 - radius as u16,
-                    left_edge as u16,
-                    height as u16 - bot as u16 + radius as u16,
-                    left_edge as u16 + button_width as u16
-                ));
-            }
-        }
-
-        modified_regions
+            (left_edge.floor() + offset, right_edge + offset)
+        }).collect()
+    }
+    fn hit(&self, config: &Config, width: u16, height: u16, x: f64, y: f64) -> Option<usize> {
+        if y <= 0.1 * height as f64 || y >= 0.9 * height as f64 {
+            return None;
+        }
+        self.edges(config, width).iter().enumerate().position(|(i, &(left_edge, right_edge))| {
+            self.buttons[i].visible() && x >= left_edge && x < right_edge
+        })
+    }
+    fn button_hit(&self, idx: usize, config: &Config, width: u16, height: u16, x: f64, y: f64) -> bool {
+        self.hit(config, width, height, x, y) == Some(idx)
+    }
+    // The shown buttons as the render thread draws them. Buttons count as drawn
+    // once they are in a scene, so only the ones that change after are redrawn.
+    fn scene(&mut self, config: &Config, width: u16, pixel_shift: (f64, f64), indicators: &[bool], complete_redraw: bool) -> Vec<ButtonScene> {
+        let pixel_shift_width = if config.enable_pixel_shift { PIXEL_SHIFT_WIDTH_PX } else { 0 };
+        let layout = self.layout(config, (width as i32 - pixel_shift_width as i32) as f64);
+        let edges = self.edges(config, width);
+        let mut buttons = Vec::new();
+        for i in 0..self.buttons.len() {
+            if !self.buttons[i].visible() {
+                continue;
+            }
+            let (prev, next) = self.visible_neighbours(i);
+            let round_left = prev.map_or(true, |prev| !self.joined(config, prev, i));
+            let round_right = next.map_or(true, |next| !self.joined(config, i, next));
+            let button_width = layout[i].1;
+            let (left_edge, right_edge) = (edges[i].0 + pixel_shift.0, edges[i].1 + pixel_shift.0);
+            let button = &mut self.buttons[i];
+            let indicated = indicators.get(i).copied().unwrap_or(false)
+                || (matches!(button.action, Action::Quiet) && QUIET.load(Ordering::Relaxed));
+            let changed = complete_redraw || button.needs_redraw(config);
+            let level = button.get_level(config);
+            let progress = button.hold_progress(config).unwrap_or(0.0);
+            if changed {
+                button.changed = false;
+                button.last_rendered_level = level;
+                button.last_rendered_progress = progress;
+            }
+            buttons.push(ButtonScene {
+                image: if button.armed_at.is_some() { Image::Text(CONFIRM_LABEL.to_string()) } else { button.image.clone() },
+                icon_color: button.icon_color,
+                left_edge,
+                width: button_width,
+                right_edge,
+                round_left,
+                round_right,
+                color: button.get_color(config, indicated),
+                level,
+                progress,
+                changed,
+            });
+        }
+        buttons
     }
 }
 
@@ -314,17 +611,43 @@ impl LibinputInterface for Interface {
 }
 
 
-fn button_hit(num: u32, idx: u32, width: u16, height: u16, x: f64, y: f64) -> bool {
-    let button_width = (width as i32 - (BUTTON_SPACING_PX * (num - 1) as i32)) as f64 / num as f64;
-    let left_edge = idx as f64 * (button_width + BUTTON_SPACING_PX as f64);
-    if x < left_edge || x > (left_edge + button_width) {
-        return false
+// What sending key events needs of the virtual input device
+trait EventSink {
+    fn write(&mut self, events: &[input_event]) -> io::Result<usize>;
+}
+
+impl<F> EventSink for UInputHandle<F> where F: AsRawFd {
+    fn write(&mut self, events: &[input_event]) -> io::Result<usize> {
+        UInputHandle::write(self, events)
+    }
+}
+
+// Writes all events, retrying what the kernel did not take.
+// Gives up after a few attempts without progress.
+fn write_events<W>(uinput: &mut W, events: &[input_event]) -> io::Result<()> where W: EventSink {
+    let mut written = 0;
+    let mut attempts = 0;
+    while written < events.len() {
+        match uinput.write(&events[written..]) {
+            Ok(n) if n > 0 => {
+                written += n;
+                attempts = 0;
+                continue;
+            }
+            Ok(_) => {},
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {},
+            Err(e) => return Err(e),
+        }
+        attempts += 1;
+        if attempts >= UINPUT_WRITE_ATTEMPTS {
+            return Err(io::Error::new(ErrorKind::WriteZero, format!("wrote {} of {} events", written, events.len())));
+        }
     }
-    y > 0.1 * height as f64 && y < 0.9 * height as f64
+    Ok(())
 }
 
-fn emit<F>(uinput: &mut UInputHandle<F>, ty: EventKind, code: u16, value: i32) where F: AsRawFd {
-    uinput.write(&[input_event {
+fn emit<W>(uinput: &mut W, ty: EventKind, code: u16, value: i32) where W: EventSink {
+    let event = input_event {
         value: value,
         type_: ty as u16,
         code: code,
@@ -332,26 +655,315 @@ fn emit<F>(uinput: &mut UInputHandle<F>, ty: EventKind, code: u16, value: i32) w
             tv_sec: 0,
             tv_usec: 0
         }
-    }]).unwrap();
+    };
+    if let Err(e) = write_events(uinput, &[event]) {
+        // the main loop recreates the device, losing this event is better than stopping
+        if !UINPUT_FAILED.swap(true, Ordering::Relaxed) {
+            println!("Failed to write to the virtual input device: {}", e);
+            event_log::record(Record::Error("uinput write failed"));
+        }
+    }
+}
+
+// Opens /dev/uinput, which may only show up a moment after boot once the
+// module is loaded. Needs to be called before dropping privileges.
+fn open_uinput(missing: MissingUinput) -> Option<File> {
+    let mut delay_ms = 100;
+    loop {
+        match OpenOptions::new().write(true).open("/dev/uinput") {
+            Ok(file) => return Some(file),
+            Err(e) => println!("Failed to open /dev/uinput: {}. Is the uinput module loaded? Try `modprobe uinput`", e),
+        }
+        if missing == MissingUinput::DisplayOnly {
+            println!("Continuing without sending keys until /dev/uinput can be opened");
+            return None;
+        }
+        println!("Trying again in {} ms", delay_ms);
+        thread::sleep(Duration::from_millis(delay_ms));
+        delay_ms = min(delay_ms * 2, UINPUT_OPEN_MAX_DELAY_MS);
+    }
 }
 
-fn toggle_key<F>(uinput: &mut UInputHandle<F>, code: Key, value: i32) where F: AsRawFd {
+// Registers every key the layers can send, which has to happen before creating the device
+fn setup_uinput_device<F>(uinput: &mut UInputHandle<F>, layers: &[Vec<FunctionLayer>], advertise_keyboard: bool) -> io::Result<()> where F: AsRawFd {
+    uinput.set_evbit(EventKind::Key)?;
+    if advertise_keyboard {
+        for code in 1..=Key::MicMute as u16 {
+            if let Ok(key) = Key::from_code(code) {
+                uinput.set_keybit(key)?;
+            }
+        }
+        // the kernel fills in its default repeat delay and rate
+        uinput.set_evbit(EventKind::Autorepeat)?;
+    }
+    for layer in layers.iter().flatten() {
+        for button in &layer.buttons {
+            for action in button.actions() {
+                match action {
+                    Action::Key(key) | Action::MouseButton(key) => uinput.set_keybit(*key)?,
+                    Action::Text(_) => for key in text_input::text_keys() {
+                        uinput.set_keybit(key)?;
+                    },
+                    Action::Scroll(_) | Action::Layer(_) | Action::DumpState | Action::Command(_) | Action::Quiet => {},
+                }
+            }
+            if let Some(slider) = &button.slider {
+                uinput.set_keybit(slider.decrease)?;
+                uinput.set_keybit(slider.increase)?;
+            }
+        }
+    }
+    // The keypad is always registered, so that a numpad layer can be
+    // added by reloading the config without restarting
+    for key in KEYPAD_KEYS {
+        uinput.set_keybit(key)?;
+    }
+    // Only advertise a wheel when it is actually used, as it changes
+    // how desktops classify the device
+    if uses_wheel(layers) {
+        uinput.set_evbit(EventKind::Relative)?;
+        uinput.set_relbit(RelativeAxis::Wheel)?;
+    }
+    create_uinput_device(uinput)
+}
+
+// Whether any button scrolls, which needs the wheel axis on the virtual device
+fn uses_wheel(layers: &[Vec<FunctionLayer>]) -> bool {
+    layers.iter().flatten()
+        .flat_map(|layer| &layer.buttons)
+        .any(|button| button.actions().any(|action| matches!(action, Action::Scroll(_))))
+}
+
+// How the virtual device identifies itself, to tell its events from the user's
+const VIRTUAL_VENDOR: u16 = 0x1209;
+const VIRTUAL_PRODUCT: u16 = 0x316E;
+
+fn create_uinput_device<F>(uinput: &mut UInputHandle<F>) -> io::Result<()> where F: AsRawFd {
+    let mut dev_name_c = [0 as c_char; 80];
+    let dev_name = "Dynamic Function Row Virtual Input Device".as_bytes();
+    for i in 0..dev_name.len() {
+        dev_name_c[i] = dev_name[i] as c_char;
+    }
+    uinput.dev_setup(&uinput_setup {
+        id: input_id {
+            bustype: 0x19,
+            vendor: VIRTUAL_VENDOR,
+            product: VIRTUAL_PRODUCT,
+            version: 1
+        },
+        ff_effects_max: 0,
+        name: dev_name_c
+    })?;
+    uinput.dev_create()
+}
+
+// The kernel keeps the registered keys when the device is destroyed,
+// so it only needs to be created again on the same file descriptor
+fn recreate_uinput_device<F>(uinput: &mut UInputHandle<F>) where F: AsRawFd {
+    println!("Recreating the virtual input device");
+    _ = uinput.dev_destroy();
+    if let Err(e) = create_uinput_device(uinput) {
+        println!("Failed to recreate the virtual input device: {}", e);
+        UINPUT_FAILED.store(true, Ordering::Relaxed);
+    }
+}
+
+fn toggle_key<W>(uinput: &mut W, code: Key, value: i32) where W: EventSink {
     emit(uinput, EventKind::Key, code as u16, value);
     emit(uinput, EventKind::Synchronize, SynchronizeKind::Report as u16, 0);
 }
 
+fn perform_action<W>(uinput: &mut W, action: &Action, active: bool) where W: EventSink {
+    match action {
+        Action::Key(key) | Action::MouseButton(key) => toggle_key(uinput, *key, active as i32),
+        Action::Text(text) => if active {
+            type_text(uinput, text);
+        },
+        Action::Scroll(amount) => if active {
+            emit(uinput, EventKind::Relative, RelativeAxis::Wheel as u16, *amount);
+            emit(uinput, EventKind::Synchronize, SynchronizeKind::Report as u16, 0);
+        },
+        Action::Command(command) => if active {
+            run_command(command);
+        },
+        // these are handled by the touchbar itself
+        Action::Layer(_) | Action::DumpState | Action::Quiet => {},
+    }
+}
+
+fn emit_slider_steps<W>(uinput: &mut W, slider: &Slider, steps: i32) where W: EventSink {
+    let key = if steps > 0 { slider.increase } else { slider.decrease };
+    for _ in 0..steps.abs() {
+        toggle_key(uinput, key, 1);
+        toggle_key(uinput, key, 0);
+    }
+}
+
+fn type_text<W>(uinput: &mut W, text: &str) where W: EventSink {
+    for stroke in text_input::strokes(text) {
+        if stroke.ctrl {
+            toggle_key(uinput, Key::LeftCtrl, 1);
+        }
+        if stroke.shift {
+            toggle_key(uinput, Key::LeftShift, 1);
+        }
+        toggle_key(uinput, stroke.key, 1);
+        toggle_key(uinput, stroke.key, 0);
+        if stroke.shift {
+            toggle_key(uinput, Key::LeftShift, 0);
+        }
+        if stroke.ctrl {
+            toggle_key(uinput, Key::LeftCtrl, 0);
+        }
+    }
+}
+
+// The command is not waited for on the main loop, a thread collects it once it exits
+fn run_command(command: &str) {
+    match process::Command::new("sh").arg("-c").arg(command).stdin(Stdio::null()).spawn() {
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => println!("Failed to run {}: {}", command, e),
+    }
+}
+
+// Cycle buttons keep their entry across config reloads if their entries did not
+// change, the others start at the entry reported by their CycleQuery
+fn sync_cycles(layers: &mut [Vec<FunctionLayer>], previous: &[Vec<FunctionLayer>]) {
+    for (bar_idx, bar_layers) in layers.iter_mut().enumerate() {
+        for layer in bar_layers {
+            let previous_layer = previous.get(bar_idx).and_then(|p| p.iter().find(|l| l.name == layer.name));
+            for (i, button) in layer.buttons.iter_mut().enumerate() {
+                let Some(cycle) = &mut button.cycle else {
+                    continue;
+                };
+                let kept = previous_layer
+                    .and_then(|l| l.buttons.get(i))
+                    .and_then(|b| b.cycle.as_ref())
+                    .filter(|old| old.entries == cycle.entries)
+                    .map(|old| old.current);
+                let current = match kept {
+                    Some(current) => current,
+                    None => {
+                        cycle.query_state();
+                        cycle.current
+                    }
+                };
+                button.show_cycle_entry(current);
+            }
+        }
+    }
+}
+
+// What StateFile keeps across restarts
+fn current_state<S>(bars: &[Bar<S>], layers: &[Vec<FunctionLayer>]) -> SavedState {
+    SavedState {
+        quiet: QUIET.load(Ordering::Relaxed),
+        bars: bars.iter().zip(layers).map(|(bar, layers)| BarState {
+            layer: bar.layer_override.map(|layer| layers[layer].name.clone()),
+            cycles: layers.iter().map(|layer| {
+                let cycles: BTreeMap<usize, String> = layer.buttons.iter().enumerate()
+                    .filter_map(|(i, button)| Some((i, button.cycle.as_ref()?.current().text.clone())))
+                    .collect();
+                (layer.name.clone(), cycles)
+            }).filter(|(_, cycles)| !cycles.is_empty()).collect(),
+        }).collect(),
+    }
+}
+
+// Puts the buttons back the way they were saved. Cycle buttons perform the action
+// of their entry again so that it matches the button, unless their CycleQuery
+// already found out the actual state.
+fn restore_state<S, W>(saved: &SavedState, bars: &mut [Bar<S>], layers: &mut [Vec<FunctionLayer>], uinput: &mut W) where W: EventSink {
+    QUIET.store(saved.quiet, Ordering::Relaxed);
+    for ((bar, layers), saved) in bars.iter_mut().zip(layers.iter_mut()).zip(&saved.bars) {
+        if let Some(name) = &saved.layer {
+            // the primary and media layer follow Fn and are never saved
+            match layers.iter().position(|l| &l.name == name) {
+                Some(layer) if layer >= 2 => bar.layer_override = Some(layer),
+                _ => println!("Not restoring layer {}, it no longer exists", name),
+            }
+        }
+        for (name, cycles) in &saved.cycles {
+            let Some(layer) = layers.iter_mut().find(|l| &l.name == name) else {
+                println!("Not restoring the buttons of layer {}, it no longer exists", name);
+                continue;
+            };
+            for (&idx, text) in cycles {
+                let Some(button) = layer.buttons.get_mut(idx) else {
+                    println!("Not restoring button {} of layer {}, it no longer exists", idx, name);
+                    continue;
+                };
+                let Some(cycle) = &button.cycle else {
+                    println!("Not restoring button {} of layer {}, it is no Cycle anymore", idx, name);
+                    continue;
+                };
+                let Some(entry) = cycle.entries.iter().position(|entry| &entry.text == text) else {
+                    println!("Not restoring button {} of layer {}, it has no entry {} anymore", idx, name, text);
+                    continue;
+                };
+                if cycle.has_query() {
+                    continue;
+                }
+                button.show_cycle_entry(entry);
+                let action = button.action.clone();
+                perform_action(uinput, &action, true);
+                perform_action(uinput, &action, false);
+            }
+        }
+    }
+}
+
 fn main() {
-    let mut drm = DrmBackend::open_card().unwrap();
-    let (height, width) = drm.mode().size();
-    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
-        real_main(&mut drm)
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--check-config") {
+        match config::check_config(args.get(1).map(String::as_str)) {
+            Ok(()) => println!("Config OK"),
+            Err(e) => {
+                eprintln!("Config error: {:#}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if !args.is_empty() {
+        process::exit(control::run_client(&args));
+    }
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        eprintln!("Recent events, oldest first:\n{}", event_log::format());
     }));
+    let drms: Vec<Mutex<DrmBackend>> = DrmBackend::open_cards().unwrap().into_iter().map(Mutex::new).collect();
+    // the scope joins the threads showing the frames, also after a panic,
+    // so that they can not draw over the crash bitmap
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        thread::scope(|scope| real_main(scope, &drms))
+    }));
+    if result.is_ok() {
+        return;
+    }
+    for drm in &drms {
+        draw_crash_bitmap(&mut drm.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+    let mut sigset = SigSet::empty();
+    sigset.add(Signal::SIGTERM);
+    sigset.wait().unwrap();
+}
+
+fn draw_crash_bitmap(drm: &mut DrmBackend) {
+    let (height, width) = drm.mode().size();
     let crash_bitmap = include_bytes!("crash_bitmap.raw");
     let mut map = drm.map().unwrap();
     let data = map.as_mut();
     let mut wptr = 0;
-    for byte in crash_bitmap {
+    'outer: for byte in crash_bitmap {
         for i in 0..8 {
+            // the bitmap is made for the built-in touchbar, other displays only show part of it
+            if wptr + 4 > data.len() {
+                break 'outer;
+            }
             let bit = ((byte >> i) & 0x1) == 0;
             let color = if bit { 0xFF } else { 0x0 };
             data[wptr] = color;
@@ -363,19 +975,447 @@ fn main() {
     }
     drop(map);
     drm.dirty(&[ClipRect::new(0, 0, height as u16, width as u16)]).unwrap();
-    let mut sigset = SigSet::empty();
-    sigset.add(Signal::SIGTERM);
-    sigset.wait().unwrap();
 }
 
-fn real_main(drm: &mut DrmBackend) {
-    let (height, width) = drm.mode().size();
-    let (db_width, db_height) = drm.fb_info().unwrap().size();
-    let mut uinput = UInputHandle::new(OpenOptions::new().write(true).open("/dev/uinput").unwrap());
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DeviceKind {
+    Digitizer,
+    Keyboard,
+    Other,
+}
+
+const DEVICE_CAPABILITIES: [DeviceCapability; 7] = [
+    DeviceCapability::Keyboard, DeviceCapability::Pointer, DeviceCapability::Touch, DeviceCapability::TabletTool,
+    DeviceCapability::TabletPad, DeviceCapability::Gesture, DeviceCapability::Switch,
+];
+
+// What a device libinput reports is used for. The touchbar comes with other
+// devices named after it, like its keyboard for the function keys, which
+// neither touch nor count as keyboards for DockedKeyboard.
+fn classify_device(name: &str, capabilities: &[DeviceCapability]) -> DeviceKind {
+    if name.contains(" Touch Bar") {
+        if capabilities.contains(&DeviceCapability::Touch) {
+            DeviceKind::Digitizer
+        } else {
+            DeviceKind::Other
+        }
+    } else if capabilities.contains(&DeviceCapability::Keyboard) {
+        DeviceKind::Keyboard
+    } else {
+        DeviceKind::Other
+    }
+}
+
+// The virtual device has keys of its own, which are not the user typing,
+// and with AdvertiseKeyboard libinput sees a keyboard in it
+fn is_own_device(dev: &InputDevice) -> bool {
+    dev.id_vendor() == VIRTUAL_VENDOR as u32 && dev.id_product() == VIRTUAL_PRODUCT as u32
+}
+
+fn device_kind(dev: &InputDevice) -> DeviceKind {
+    if is_own_device(dev) {
+        return DeviceKind::Other;
+    }
+    let capabilities: Vec<DeviceCapability> = DEVICE_CAPABILITIES.into_iter().filter(|&cap| dev.has_capability(cap)).collect();
+    classify_device(dev.name(), &capabilities)
+}
+
+// Picks which of the displays without a digitizer a new one belongs to, by how
+// much of their sysfs paths they share. The display and the digitizer of a
+// touchbar are functions of the same USB device, so that is the parent they
+// share, which does not depend on the order in which they are enumerated.
+// Displays that are as close as each other go by the order they were found in.
+fn closest_display(free: &[(usize, Option<&Path>)], digitizer: Option<&Path>) -> Option<usize> {
+    let shared = |display: Option<&Path>| match (display, digitizer) {
+        (Some(display), Some(digitizer)) => display.components().zip(digitizer.components()).take_while(|(a, b)| a == b).count(),
+        _ => 0,
+    };
+    free.iter()
+        .min_by_key(|&&(_, display)| std::cmp::Reverse(shared(display)))
+        .map(|&(i, _)| i)
+}
+
+// Tracks a new touch unless MaxTouches are down already, in which case it is counted as ignored
+fn track_touch(down_slots: &mut HashSet<u32>, slot: u32, max_touches: usize) -> bool {
+    if down_slots.len() >= max_touches {
+        counters::add(&IGNORED_TOUCHES, 1);
+        return false;
+    }
+    down_slots.insert(slot);
+    true
+}
+
+// Forgets the touch in the given slot. Returns the layer and button it was on
+// once no other touch is on it, as a button pressed with two fingers stays
+// pressed until both are lifted.
+fn lift_touch(touches: &mut HashMap<u32, (usize, usize)>, slot: u32) -> Option<(usize, usize)> {
+    let touch = touches.remove(&slot)?;
+    if touches.values().any(|&other| other == touch) {
+        return None;
+    }
+    Some(touch)
+}
+
+// Changes in between frames are drawn together in the next one, key events
+// are sent as they happen regardless. Returns how long until the next frame
+// when it is too early for one.
+fn frame_wait_ms(last_frame: Instant, now: Instant, frame_interval_ms: u32) -> Option<i32> {
+    let since_last_frame = now.saturating_duration_since(last_frame).as_millis() as i32;
+    (since_last_frame < frame_interval_ms as i32).then(|| frame_interval_ms as i32 - since_last_frame)
+}
+
+// The display of a bar and the thread drawing it
+struct Screen<'a> {
+    drm: &'a Mutex<DrmBackend>,
+    renderer: Renderer,
+}
+
+// State of one touchbar display and the digitizer on top of it. The tests
+// go without a screen, everything but drawing works the same.
+struct Bar<S> {
+    screen: S,
+    width: u16,
+    height: u16,
+    // 1 while Fn is held
+    active_layer: usize,
+    // 1 while docked, if that swaps the layers
+    base_layer: usize,
+    // an extra layer picked with a Layer action, shown instead of the others
+    layer_override: Option<usize>,
+    needs_complete_redraw: bool,
+    digitizer: Option<InputDevice>,
+    sysfs_device: Option<PathBuf>,
+    // every finger on the bar, whether it is on a button or not, up to MaxTouches
+    down_slots: HashSet<u32>,
+    touches: HashMap<u32, (usize, usize)>,
+    // a touch that may still turn into a two finger peek, it has not pressed anything yet
+    pending_touch: Option<PendingTouch>,
+    // fingers of the peek gesture, the other layer is shown while any of them is down
+    peek_slots: Vec<u32>,
+    // new touches are ignored until then, after a gesture
+    gesture_until: Instant,
+    watchdog: DisplayWatchdog,
+    last_frame: Instant,
+    // the next frame had to wait for FrameInterval or the one before
+    frame_deferred: bool,
+    // layer in the last scene, to notice when a different one is shown
+    drawn_layer: usize,
+    // a short message drawn over the buttons
+    toast: Option<Toast>,
+    // a DumpState press, the snapshot of the frame is asked for before the next one
+    dump_requested: bool,
+}
+
+#[derive(Clone, Copy)]
+struct PendingTouch {
+    slot: u32,
+    x: f64,
+    y: f64,
+    at: Instant,
+}
+
+impl<'a> Bar<Screen<'a>> {
+    fn new(drm: &'a Mutex<DrmBackend>, renderer: Renderer) -> Bar<Screen<'a>> {
+        let (height, width) = drm.lock().unwrap().mode().size();
+        let sysfs_device = drm.lock().unwrap().sysfs_device();
+        Bar::with_screen(Screen { drm, renderer }, width, height, sysfs_device)
+    }
+    fn finish_dump(&mut self, cfg: &Config, frame: Result<Snapshot>) {
+        // the frame is the last one the render thread drew
+        let dump = frame.map(|frame| StateDump {
+            width: frame.width,
+            height: frame.height,
+            stride: frame.stride,
+            frame: frame.data,
+            config: cfg.source.clone(),
+            display: self.screen.drm.lock().unwrap().describe(),
+            events: event_log::format(),
+        });
+        let message = match dump.and_then(dump::write) {
+            Ok(path) => path.display().to_string(),
+            Err(e) => {
+                println!("Failed to dump state: {}", e);
+                "Dump failed".to_string()
+            }
+        };
+        if QUIET.load(Ordering::Relaxed) {
+            println!("Dumped state: {}", message);
+            return;
+        }
+        self.toast = Some(Toast { text: message, shown_at: Instant::now(), preview_of: None });
+        self.needs_complete_redraw = true;
+    }
+}
+
+impl<S> Bar<S> {
+    fn with_screen(screen: S, width: u16, height: u16, sysfs_device: Option<PathBuf>) -> Bar<S> {
+        Bar {
+            screen, width, height,
+            active_layer: 0,
+            base_layer: 0,
+            layer_override: None,
+            needs_complete_redraw: true,
+            digitizer: None,
+            sysfs_device,
+            down_slots: HashSet::new(),
+            touches: HashMap::new(),
+            pending_touch: None,
+            peek_slots: Vec::new(),
+            gesture_until: Instant::now(),
+            watchdog: DisplayWatchdog::new(),
+            last_frame: Instant::now(),
+            frame_deferred: false,
+            drawn_layer: 0,
+            toast: None,
+            dump_requested: false,
+        }
+    }
+    fn shown_layer(&self) -> usize {
+        if let Some(layer) = self.layer_override {
+            return layer;
+        }
+        let layer = self.active_layer ^ self.base_layer;
+        if self.peek_slots.is_empty() {
+            layer
+        } else {
+            1 - layer
+        }
+    }
+    fn press<W>(&mut self, layers: &mut [FunctionLayer], slot: u32, x: f64, y: f64, cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer, modifiers: Modifiers) where W: EventSink {
+        let layer = self.shown_layer();
+        if let Some(btn) = layers[layer].hit(cfg, self.width, self.height, x, y) {
+            if layers[layer].buttons[btn].in_dead_time(cfg) {
+                counters::add(&IGNORED_TOUCHES, 1);
+                return;
+            }
+            self.touches.insert(slot, (layer, btn));
+            layers[layer].set_active(btn, cfg, uinput, stats, control, modifiers, true);
+            if let Some(slider) = &mut layers[layer].buttons[btn].slider {
+                slider.start(x);
+            }
+            match layers[layer].buttons[btn].pressed_action.clone() {
+                Some(Action::Layer(name)) => {
+                    let target = layers.iter().position(|l| l.name == name).unwrap();
+                    // the primary and media layer go back to following Fn
+                    let layer_override = if target < 2 { None } else { Some(target) };
+                    if self.layer_override != layer_override {
+                        layers[layer].disarm();
+                        self.layer_override = layer_override;
+                        STATE_CHANGED.store(true, Ordering::Relaxed);
+                        self.needs_complete_redraw = true;
+                    }
+                }
+                Some(Action::DumpState) => self.dump_requested = true,
+                Some(Action::Quiet) => _ = QUIET.fetch_xor(true, Ordering::Relaxed),
+                _ => {}
+            }
+        }
+    }
+    // A touch that is not ignored landing on the shown layer, `hit` is the button under it
+    fn touch_down<W>(&mut self, layers: &mut [FunctionLayer], slot: u32, x: f64, y: f64, hit: Option<usize>, cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer, modifiers: Modifiers) where W: EventSink {
+        let layer = self.shown_layer();
+        // buttons with a TwoFingerAction wait for a second finger
+        // on them just like touches do for a peek
+        let two_finger_btn = hit.filter(|&btn| layers[layer].buttons[btn].two_finger_action.is_some());
+        self.flush_pending_touch(layers, cfg, uinput, stats, control, modifiers);
+        if let Some(pending) = self.pending_touch {
+            if two_finger_btn.is_some() && layers[layer].hit(cfg, self.width, self.height, pending.x, pending.y) == two_finger_btn {
+                self.pending_touch = None;
+                self.press_two_finger(layers, pending, slot, two_finger_btn.unwrap(), cfg, uinput, stats, control, modifiers);
+                return;
+            }
+        }
+        if !cfg.two_finger_peek {
+            // the touch waiting for a second finger on its button did not get one
+            if let Some(pending) = self.pending_touch.take() {
+                self.press(layers, pending.slot, pending.x, pending.y, cfg, uinput, stats, control, modifiers);
+            }
+            match two_finger_btn {
+                Some(_) => self.pending_touch = Some(PendingTouch { slot, x, y, at: Instant::now() }),
+                None => self.press(layers, slot, x, y, cfg, uinput, stats, control, modifiers),
+            }
+            return;
+        }
+        // more fingers joining a peek are ignored as well
+        if !self.peek_slots.is_empty() {
+            self.peek_slots.push(slot);
+            return;
+        }
+        match self.pending_touch.take() {
+            Some(pending) => {
+                layers[self.shown_layer()].disarm();
+                self.peek_slots = vec![pending.slot, slot];
+                self.needs_complete_redraw = true;
+            }
+            None => self.pending_touch = Some(PendingTouch { slot, x, y, at: Instant::now() }),
+        }
+    }
+    // Returns false for a touch over MaxTouches. It is not tracked at all,
+    // so its motion and lifting do nothing either.
+    fn track_down<W>(&mut self, layers: &mut [FunctionLayer], slot: u32, cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer) -> bool where W: EventSink {
+        if track_touch(&mut self.down_slots, slot, cfg.max_touches) {
+            return true;
+        }
+        if cfg.extra_touches == ExtraTouches::CancelAll {
+            self.cancel_touches(layers, cfg, uinput, stats, control);
+        }
+        false
+    }
+    fn lift<W>(&mut self, layers: &mut [FunctionLayer], slot: u32, cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer, modifiers: Modifiers) where W: EventSink {
+        self.down_slots.remove(&slot);
+        // the peek lasts until the last of its fingers is lifted
+        if let Some(i) = self.peek_slots.iter().position(|&s| s == slot) {
+            if self.peek_slots.len() == 1 {
+                layers[self.shown_layer()].disarm();
+            }
+            self.peek_slots.remove(i);
+            if self.peek_slots.is_empty() {
+                self.gesture_until = Instant::now() + Duration::from_millis(cfg.gesture_cooldown_ms as u64);
+                self.needs_complete_redraw = true;
+            }
+            return;
+        }
+        // a tap shorter than the peek window
+        if let Some(pending) = self.pending_touch.filter(|p| p.slot == slot) {
+            self.pending_touch = None;
+            self.press(layers, slot, pending.x, pending.y, cfg, uinput, stats, control, modifiers);
+        }
+        self.release(layers, slot, cfg, uinput, stats, control);
+    }
+    fn release<W>(&mut self, layers: &mut [FunctionLayer], slot: u32, cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer) where W: EventSink {
+        let Some((layer, btn)) = lift_touch(&mut self.touches, slot) else {
+            return;
+        };
+        layers[layer].set_active(btn, cfg, uinput, stats, control, Modifiers::default(), false);
+        let button = &mut layers[layer].buttons[btn];
+        if let Some(slider) = &mut button.slider {
+            if !slider.end() {
+                perform_action(uinput, &button.action, true);
+                perform_action(uinput, &button.action, false);
+            }
+        }
+    }
+    // Lets go of everything the touches on the bar are doing, for when their
+    // lifting is not going to be reported or should not count anymore.
+    // Fingers that stay down do nothing until they are lifted.
+    fn cancel_touches<W>(&mut self, layers: &mut [FunctionLayer], cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer) where W: EventSink {
+        let slots: Vec<u32> = self.touches.keys().copied().collect();
+        counters::add(&FORCED_RELEASES, slots.len() as u64);
+        for slot in slots {
+            self.release(layers, slot, cfg, uinput, stats, control);
+        }
+        self.pending_touch = None;
+        if !self.peek_slots.is_empty() {
+            layers[self.shown_layer()].disarm();
+            self.peek_slots.clear();
+            self.needs_complete_redraw = true;
+        }
+    }
+    // Buttons that got hidden are not drawn anymore, so they would never get to
+    // clear their changed state. Touches on them are let go of like when canceled.
+    fn release_hidden<W>(&mut self, layers: &mut [FunctionLayer], cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer) where W: EventSink {
+        let slots: Vec<u32> = self.touches.iter()
+            .filter(|(_, &(layer, btn))| !layers[layer].buttons[btn].visible())
+            .map(|(&slot, _)| slot)
+            .collect();
+        counters::add(&FORCED_RELEASES, slots.len() as u64);
+        for slot in slots {
+            self.release(layers, slot, cfg, uinput, stats, control);
+        }
+        for button in layers.iter_mut().flat_map(|layer| &mut layer.buttons) {
+            if !button.visible() {
+                button.disarm();
+                button.active = false;
+                button.changed = false;
+            }
+        }
+    }
+    // Shows what an icon button does once it is held for HoldPreviewDelay, and hides
+    // it again as soon as the button is released. Returns when to check again.
+    fn update_hold_preview(&mut self, layers: &[FunctionLayer], cfg: &Config) -> i32 {
+        let mut next_timeout_ms = i32::MAX;
+        let mut held = None;
+        if cfg.hold_preview_ms > 0 && !QUIET.load(Ordering::Relaxed) {
+            for &(layer, btn) in self.touches.values() {
+                let button = &layers[layer].buttons[btn];
+                if !button.active || matches!(button.image, Image::Text(_)) {
+                    continue;
+                }
+                let held_for = button.last_action.1.elapsed().as_millis() as i32;
+                if held_for >= cfg.hold_preview_ms as i32 {
+                    held = Some((layer, btn));
+                } else {
+                    next_timeout_ms = min(next_timeout_ms, cfg.hold_preview_ms as i32 - held_for);
+                }
+            }
+        }
+        let previewed = self.toast.as_ref().and_then(|toast| toast.preview_of);
+        if previewed == held {
+            return next_timeout_ms;
+        }
+        // a preview takes the place of any other toast, which would be covered by it anyway
+        self.toast = held.map(|(layer, btn)| Toast {
+            text: layers[layer].buttons[btn].action.describe(),
+            shown_at: Instant::now(),
+            preview_of: held,
+        });
+        self.needs_complete_redraw = true;
+        next_timeout_ms
+    }
+    // Presses the button under a touch that did not turn into a peek in time
+    fn flush_pending_touch<W>(&mut self, layers: &mut [FunctionLayer], cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer, modifiers: Modifiers) -> i32 where W: EventSink {
+        let Some(pending) = self.pending_touch else {
+            return i32::MAX;
+        };
+        let waited = pending.at.elapsed().as_millis() as i32;
+        if waited < PEEK_WINDOW_MS {
+            return PEEK_WINDOW_MS - waited;
+        }
+        self.pending_touch = None;
+        self.press(layers, pending.slot, pending.x, pending.y, cfg, uinput, stats, control, modifiers);
+        i32::MAX
+    }
+    // Presses a button with its TwoFingerAction, for two touches that landed on it
+    // within the peek window. Both touches hold it down until they are lifted.
+    fn press_two_finger<W>(&mut self, layers: &mut [FunctionLayer], pending: PendingTouch, slot: u32, btn: usize, cfg: &Config, uinput: &mut W, stats: &mut UsageStats, control: &mut ControlServer, modifiers: Modifiers) where W: EventSink {
+        let layer = self.shown_layer();
+        layers[layer].buttons[btn].two_finger_press = true;
+        self.press(layers, pending.slot, pending.x, pending.y, cfg, uinput, stats, control, modifiers);
+        layers[layer].buttons[btn].two_finger_press = false;
+        if self.touches.contains_key(&pending.slot) {
+            self.touches.insert(slot, (layer, btn));
+        }
+    }
+}
+
+fn real_main<'scope, 'env>(scope: &'scope Scope<'scope, 'env>, drms: &'env [Mutex<DrmBackend>]) {
+    let mut bars: Vec<Bar<Screen>> = drms.iter().enumerate().map(|(i, drm)| Bar::new(drm, Renderer::spawn(scope, drm, i))).collect();
+    let widths: Vec<u16> = bars.iter().map(|bar| bar.width).collect();
     let mut backlight = BacklightManager::new();
     let mut cfg_mgr = ConfigManager::new();
-    let (mut cfg, mut layers) = cfg_mgr.load_config(width);
+    let (mut cfg, mut layers) = cfg_mgr.load_config(&widths);
+    let mut style = Arc::new(Style::from_config(&cfg));
+    let uinput_file = open_uinput(cfg.missing_uinput);
+    let mut uinput_missing = uinput_file.is_none();
+    // without the virtual input device, key events are written to /dev/null
+    // instead, so that everything else keeps working the same way
+    let mut uinput = UInputHandle::new(uinput_file.unwrap_or_else(|| {
+        OpenOptions::new().write(true).open("/dev/null").unwrap()
+    }));
     let mut pixel_shift = PixelShiftManager::new();
+    let mut stats = UsageStats::new(cfg.stats_file.as_deref(), cfg.session_stats_file.as_deref());
+    let mut state_file = StateFile::new(cfg.state_file.as_deref());
+    let mut control = ControlServer::new(cfg.control_group.as_deref()).unwrap();
+    let mut leds = LedManager::new();
+    let mut modifiers = ModifierTracker::new();
+    let mut keyboards: Vec<InputDevice> = Vec::new();
+    // logs every touch down and what it hit, toggled with `tiny-dfr debug-touch on`
+    let mut debug_touch = false;
+    let mut quiet = false;
+    // layer that LayerKey asks for, which QuietLocksLayer may keep from being shown
+    let mut layer_key_layer = 0;
+    let mut early_touches: VecDeque<(Instant, TouchEvent)> = VecDeque::new();
+    let started = Instant::now();
 
     // drop privileges to input and video group
     let groups = ["input", "video"];
@@ -386,9 +1426,14 @@ fn real_main(drm: &mut DrmBackend) {
         .apply()
         .unwrap_or_else(|e| { panic!("Failed to drop privileges: {}", e) });
 
-    let mut surface = ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
-    let mut active_layer = 0;
-    let mut needs_complete_redraw = true;
+    // after dropping privileges, as the queries are commands from the config
+    sync_cycles(&mut layers, &[]);
+
+    let mut sigset = SigSet::empty();
+    sigset.add(Signal::SIGTERM);
+    sigset.add(Signal::SIGINT);
+    sigset.thread_block().unwrap();
+    let mut signals = SignalFd::with_flags(&sigset, SfdFlags::SFD_NONBLOCK).unwrap();
 
     let mut input_tb = Libinput::new_with_udev(Interface);
     let mut input_main = Libinput::new_with_udev(Interface);
@@ -398,59 +1443,289 @@ fn real_main(drm: &mut DrmBackend) {
     epoll.add(input_main.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, 0)).unwrap();
     epoll.add(input_tb.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, 1)).unwrap();
     epoll.add(cfg_mgr.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 2)).unwrap();
-    uinput.set_evbit(EventKind::Key).unwrap();
-    for layer in &layers {
-        for button in &layer.buttons {
-            uinput.set_keybit(button.action).unwrap();
-        }
+    epoll.add(control.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 3)).unwrap();
+    epoll.add(&signals, EpollEvent::new(EpollFlags::EPOLLIN, 4)).unwrap();
+    for bar in &bars {
+        epoll.add(bar.screen.renderer.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 6)).unwrap();
     }
-    let mut dev_name_c = [0 as c_char; 80];
-    let dev_name = "Dynamic Function Row Virtual Input Device".as_bytes();
-    for i in 0..dev_name.len() {
-        dev_name_c[i] = dev_name[i] as c_char;
+    let mut vt = match VtWatcher::new(cfg.session_vt) {
+        Ok(vt) => {
+            epoll.add(vt.file(), EpollEvent::new(EpollFlags::EPOLLPRI, 5)).unwrap();
+            Some(vt)
+        }
+        Err(e) => {
+            println!("Failed to watch VT switches: {}", e);
+            None
+        }
+    };
+    let mut display_released = false;
+    if !uinput_missing {
+        setup_uinput_device(&mut uinput, &layers, cfg.advertise_keyboard).unwrap();
     }
-    uinput.dev_setup(&uinput_setup {
-        id: input_id {
-            bustype: 0x19,
-            vendor: 0x1209,
-            product: 0x316E,
-            version: 1
-        },
-        ff_effects_max: 0,
-        name: dev_name_c
-    }).unwrap();
-    uinput.dev_create().unwrap();
+    let mut wheel_registered = !uinput_missing && uses_wheel(&layers);
+    // once the keys the buttons send can be sent
+    restore_state(state_file.saved(), &mut bars, &mut layers, &mut uinput);
 
-    let mut digitizer: Option<InputDevice> = None;
-    let mut touches = HashMap::new();
+    let mut last_uinput_recreate: Option<Instant> = None;
     loop {
-        if cfg_mgr.update_config(&mut cfg, &mut layers, width) {
-            active_layer = 0;
-            needs_complete_redraw = true;
+        if cfg_mgr.update_config(&mut cfg, &mut layers, &widths) {
+            style = Arc::new(Style::from_config(&cfg));
+            // axes can only be registered while the device does not exist,
+            // so a first Scroll button needs it to be created again
+            if !uinput_missing && !wheel_registered && uses_wheel(&layers) {
+                println!("Recreating the virtual input device with a wheel");
+                _ = uinput.dev_destroy();
+                match setup_uinput_device(&mut uinput, &layers, cfg.advertise_keyboard) {
+                    Ok(()) => wheel_registered = true,
+                    Err(e) => {
+                        println!("Failed to recreate the virtual input device: {}", e);
+                        UINPUT_FAILED.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+            for bar in &mut bars {
+                bar.active_layer = 0;
+                bar.layer_override = None;
+                bar.needs_complete_redraw = true;
+            }
+            STATE_CHANGED.store(true, Ordering::Relaxed);
+        }
+
+        let docked = cfg.docked_keyboard.as_ref().map_or(false, |pattern| {
+            keyboards.iter().any(|kbd| kbd.name().contains(pattern.as_str()))
+        });
+        let base_layer = (docked && cfg.docked_swaps_layers) as usize;
+        for (bar, layers) in bars.iter_mut().zip(&mut layers) {
+            if bar.base_layer != base_layer {
+                layers[bar.shown_layer()].disarm();
+                bar.base_layer = base_layer;
+                bar.needs_complete_redraw = true;
+            }
+        }
+
+        if QUIET.load(Ordering::Relaxed) != quiet {
+            quiet = !quiet;
+            STATE_CHANGED.store(true, Ordering::Relaxed);
+            println!("Quiet mode {}", if quiet { "on" } else { "off" });
+            backlight.set_quiet(quiet);
+            for bar in &mut bars {
+                if quiet {
+                    bar.toast = None;
+                }
+                bar.needs_complete_redraw = true;
+            }
+            if !quiet {
+                // catch up with what changed in the meantime
+                leds.request_poll();
+                for (bar, layers) in bars.iter_mut().zip(&mut layers) {
+                    if bar.active_layer != layer_key_layer {
+                        layers[bar.shown_layer()].disarm();
+                        bar.active_layer = layer_key_layer;
+                    }
+                }
+            }
+        }
+
+        if let Some(vt) = &mut vt {
+            vt.update();
+        }
+        let vt_next_timeout_ms = vt.as_ref().map_or(i32::MAX, |vt| vt.next_timeout_ms());
+        let release = cfg.vt_switch == VtSwitch::Release && vt.as_ref().is_some_and(|vt| !vt.is_active());
+        if release != display_released {
+            display_released = release;
+            println!("{} the touchbar display", if release { "Releasing" } else { "Taking back" });
+            for (bar, layers) in bars.iter_mut().zip(&mut layers) {
+                let mut drm = bar.screen.drm.lock().unwrap();
+                let result = if release { drm.release_master() } else { drm.acquire_master() };
+                drop(drm);
+                if let Err(e) = result {
+                    println!("Failed to switch control of the display: {}", e);
+                    if !release {
+                        bar.watchdog.commit_failed();
+                    }
+                }
+                if release {
+                    bar.cancel_touches(layers, &cfg, &mut uinput, &mut stats, &mut control);
+                }
+                bar.needs_complete_redraw = true;
+            }
+        }
+
+        let mut next_timeout_ms = min(TIMEOUT_MS, vt_next_timeout_ms);
+        if UINPUT_FAILED.load(Ordering::Relaxed) {
+            let since_recreate = last_uinput_recreate.map_or(i32::MAX, |t| t.elapsed().as_millis() as i32);
+            if since_recreate >= UINPUT_RECREATE_INTERVAL_MS {
+                UINPUT_FAILED.store(false, Ordering::Relaxed);
+                last_uinput_recreate = Some(Instant::now());
+                recreate_uinput_device(&mut uinput);
+            } else {
+                next_timeout_ms = UINPUT_RECREATE_INTERVAL_MS - since_recreate;
+            }
+        }
+        if uinput_missing {
+            let since_attempt = last_uinput_recreate.map_or(i32::MAX, |t| t.elapsed().as_millis() as i32);
+            if since_attempt >= UINPUT_RECREATE_INTERVAL_MS {
+                last_uinput_recreate = Some(Instant::now());
+                if let Ok(file) = OpenOptions::new().write(true).open("/dev/uinput") {
+                    let mut opened = UInputHandle::new(file);
+                    match setup_uinput_device(&mut opened, &layers, cfg.advertise_keyboard) {
+                        Ok(()) => {
+                            println!("Opened /dev/uinput, sending keys from now on");
+                            uinput = opened;
+                            uinput_missing = false;
+                            wheel_registered = uses_wheel(&layers);
+                        }
+                        Err(e) => println!("Failed to create the virtual input device: {}", e),
+                    }
+                }
+            } else {
+                next_timeout_ms = min(next_timeout_ms, UINPUT_RECREATE_INTERVAL_MS - since_attempt);
+            }
         }
 
-        let mut next_timeout_ms = TIMEOUT_MS;
         if cfg.enable_pixel_shift {
             let (pixel_shift_needs_redraw, pixel_shift_next_timeout_ms) = pixel_shift.update();
             if pixel_shift_needs_redraw {
-                needs_complete_redraw = true;
+                for bar in &mut bars {
+                    bar.needs_complete_redraw = true;
+                }
             }
             next_timeout_ms = min(next_timeout_ms, pixel_shift_next_timeout_ms);
         }
 
-        if needs_complete_redraw || layers[active_layer].buttons.iter().any(|b| b.needs_redraw(&cfg)) {
+        if !quiet {
+            let (leds_changed, leds_next_timeout_ms) = leds.update(&cfg.led_indicators);
+            if leds_changed {
+                for bar in &mut bars {
+                    bar.needs_complete_redraw = true;
+                }
+            }
+            next_timeout_ms = min(next_timeout_ms, leds_next_timeout_ms);
+        }
+        next_timeout_ms = min(next_timeout_ms, backlight.next_timeout_ms());
+
+        for layer in layers.iter_mut().flatten() {
+            for button in &mut layer.buttons {
+                next_timeout_ms = min(next_timeout_ms, button.expire_confirm());
+                if let Some(slider) = &mut button.slider {
+                    let (steps, slider_next_timeout_ms) = slider.flush(cfg.slider_coalesce_window_ms, cfg.slider_max_taps);
+                    emit_slider_steps(&mut uinput, slider, steps);
+                    next_timeout_ms = min(next_timeout_ms, slider_next_timeout_ms);
+                }
+            }
+        }
+
+        for (bar_idx, bar) in bars.iter_mut().enumerate() {
+            if display_released {
+                break;
+            }
+            for failure in bar.screen.renderer.failures() {
+                match failure {
+                    Failure::Frame(e) => {
+                        if cfg.display_watchdog_interval_ms == 0 {
+                            panic!("Failed to update display: {}", e);
+                        }
+                        bar.watchdog.commit_failed();
+                    }
+                    Failure::Font(e) => {
+                        println!("Failed to load font, keeping the previous one: {}", e);
+                        event_log::record(Record::Error("font load failed"));
+                    }
+                }
+            }
+            let (rebuilt, watchdog_next_timeout_ms) = bar.watchdog.update(&mut bar.screen.drm.lock().unwrap(), cfg.display_watchdog_interval_ms);
+            if rebuilt {
+                event_log::record(Record::DisplayRebuilt { bar: bar_idx });
+                counters::add(&DISPLAY_REBUILDS, 1);
+                bar.needs_complete_redraw = true;
+            }
+            next_timeout_ms = min(next_timeout_ms, watchdog_next_timeout_ms);
+        }
+
+        for (bar, layers) in bars.iter_mut().zip(&mut layers) {
+            let pending_next_timeout_ms = bar.flush_pending_touch(layers, &cfg, &mut uinput, &mut stats, &mut control, modifiers.state());
+            next_timeout_ms = min(next_timeout_ms, pending_next_timeout_ms);
+        }
+
+        for (bar_idx, (bar, layers)) in bars.iter_mut().zip(&mut layers).enumerate() {
+            if mem::take(&mut bar.dump_requested) {
+                bar.screen.renderer.request_snapshot();
+            }
+            if let Some(frame) = bar.screen.renderer.take_snapshot() {
+                bar.finish_dump(&cfg, frame);
+            }
+            next_timeout_ms = min(next_timeout_ms, bar.update_hold_preview(layers, &cfg));
+            if let Some(toast) = bar.toast.as_ref().filter(|toast| toast.preview_of.is_none()) {
+                let shown_for = toast.shown_at.elapsed().as_millis() as i32;
+                if shown_for >= TOAST_MS {
+                    bar.toast = None;
+                    bar.needs_complete_redraw = true;
+                } else {
+                    next_timeout_ms = min(next_timeout_ms, TOAST_MS - shown_for);
+                }
+            }
+            if display_released {
+                continue;
+            }
+            let layer_idx = bar.shown_layer();
+            let layer_count = layers.len();
+            let layer = &mut layers[layer_idx];
+            if !bar.needs_complete_redraw && !layer.buttons.iter().any(|b| b.visible() && b.needs_redraw(&cfg)) {
+                continue;
+            }
+            if let Some(wait_ms) = frame_wait_ms(bar.last_frame, Instant::now(), cfg.frame_interval_ms) {
+                next_timeout_ms = min(next_timeout_ms, wait_ms);
+                bar.frame_deferred = true;
+                continue;
+            }
+            // the buttons keep their changes until the frame before is shown,
+            // which wakes up the loop again
+            if bar.screen.renderer.is_busy() {
+                bar.frame_deferred = true;
+                continue;
+            }
+            if bar.frame_deferred {
+                counters::add(&DEFERRED_FRAMES, 1);
+                bar.frame_deferred = false;
+            }
+            bar.last_frame = Instant::now();
+            if layer_idx != bar.drawn_layer {
+                event_log::record(Record::LayerShown { bar: bar_idx, layer: layer_idx });
+            }
+            bar.drawn_layer = layer_idx;
             let shift = if cfg.enable_pixel_shift {
                 pixel_shift.get()
             } else {
                 (0.0, 0.0)
             };
-            let clips = layers[active_layer].draw(&cfg, width as i32, height as i32, &surface, shift, needs_complete_redraw);
-            let data = surface.data().unwrap();
-            drm.map().unwrap().as_mut()[..data.len()].copy_from_slice(&data);
-            if clips.len() > 0 {
-                drm.dirty(&clips).unwrap();
-            }
-            needs_complete_redraw = false;
+            let pixel_shift_width = if cfg.enable_pixel_shift { PIXEL_SHIFT_WIDTH_PX } else { 0 };
+            let area = (shift.0 + (pixel_shift_width / 2) as f64, (bar.width as i32 - pixel_shift_width as i32) as f64);
+            let toast = bar.toast.as_ref().and_then(|toast| {
+                // a preview is drawn over its own button, to not cover the ones next to it
+                let toast_area = match toast.preview_of {
+                    Some((preview_layer, btn)) if preview_layer == layer_idx => {
+                        let (start, button_width) = layer.layout(&cfg, area.1)[btn];
+                        Some((start.floor() + area.0, button_width))
+                    }
+                    Some(_) => None,
+                    None => Some((0.0, bar.width as f64)),
+                };
+                toast_area.map(|toast_area| (toast.text.clone(), toast_area))
+            });
+            // frames with a toast are complete ones, as it covers several buttons
+            let complete_redraw = bar.needs_complete_redraw || bar.toast.is_some();
+            bar.screen.renderer.send(Scene {
+                style: style.clone(),
+                layer: layer_idx,
+                layer_name: layer.name.clone(),
+                position: (layer_idx, layer_count),
+                buttons: layer.scene(&cfg, bar.width, shift, leds.indicators(), complete_redraw),
+                area,
+                y_shift: shift.1,
+                complete: complete_redraw,
+                toast,
+            });
+            bar.needs_complete_redraw = false;
             next_timeout_ms = (1000. / MAX_FPS) as i32;
         }
 
@@ -458,60 +1733,221 @@ fn real_main(drm: &mut DrmBackend) {
             Err(Errno::EINTR) | Ok(_) => { 0 },
             e => e.unwrap(),
         };
+        for bar in &bars {
+            bar.screen.renderer.clear_shown();
+        }
+        if let Ok(Some(_)) = signals.read_signal() {
+            if let Err(e) = stats.save() {
+                println!("Failed to save stats: {}", e);
+            }
+            if let Err(e) = stats.save_session() {
+                println!("Failed to save session stats: {}", e);
+            }
+            return;
+        }
+        for request in control.poll(&epoll) {
+            match request.command {
+                Command::Status => {
+                    request.reply(&json!({
+                        "stats": stats.to_json(),
+                        "brightness": backlight.current_bl(),
+                        "quiet": QUIET.load(Ordering::Relaxed),
+                        "brightness_override": backlight.override_level(),
+                        "flags": flags::to_json(),
+                        "counters": counters::to_json(started),
+                    }).to_string());
+                },
+                Command::ResetStats => {
+                    stats.reset();
+                    request.reply(&json!({ "ok": true }).to_string());
+                },
+                Command::Subscribe => control.subscribe(request, &epoll),
+                Command::DebugTouch(enabled) => {
+                    debug_touch = enabled;
+                    println!("Touch debugging {}", if enabled { "enabled" } else { "disabled" });
+                    request.reply(&json!({ "ok": true }).to_string());
+                },
+                Command::Quiet(enabled) => {
+                    QUIET.store(enabled, Ordering::Relaxed);
+                    request.reply(&json!({ "ok": true }).to_string());
+                },
+                Command::SetBrightness(level) => {
+                    backlight.set_override(level);
+                    request.reply(&json!({ "ok": true }).to_string());
+                },
+                Command::SetFlag(ref name, value) => {
+                    // buttons appearing or disappearing moves the others
+                    if flags::set(name, value) {
+                        for (bar, layers) in bars.iter_mut().zip(&mut layers) {
+                            bar.release_hidden(layers, &cfg, &mut uinput, &mut stats, &mut control);
+                            bar.needs_complete_redraw = true;
+                        }
+                    }
+                    request.reply(&json!({ "ok": true }).to_string());
+                },
+            }
+        }
         input_tb.dispatch().unwrap();
         input_main.dispatch().unwrap();
-        for event in &mut input_tb.clone().chain(input_main.clone()) {
+        let mut events: VecDeque<Event> = input_tb.clone().chain(input_main.clone()).collect();
+        while let Some(event) = events.pop_front() {
+            let was_dimmed = backlight.is_dimmed(&cfg);
             backlight.process_event(&event);
             match event {
+                // Digitizers are paired with the display closest to them in sysfs,
+                // see closest_display. Devices that exist at startup are reported
+                // as added as well.
                 Event::Device(DeviceEvent::Added(evt)) => {
                     let dev = evt.device();
-                    if dev.name().contains(" Touch Bar") {
-                        digitizer = Some(dev);
+                    let kind = device_kind(&dev);
+                    if kind == DeviceKind::Digitizer {
+                        // libinput applies LIBINPUT_CALIBRATION_MATRIX from udev to the
+                        // transformed coordinates on its own, this only makes it visible
+                        if let Some(matrix) = dev.config_calibration_matrix() {
+                            if matrix != [1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+                                println!("{} uses calibration matrix {:?}", dev.name(), matrix);
+                            }
+                        }
+                        let sysfs_device = fs::canonicalize(Path::new("/sys/class/input").join(dev.sysname())).ok();
+                        let free: Vec<(usize, Option<&Path>)> = bars.iter().enumerate()
+                            .filter(|(_, bar)| bar.digitizer.is_none())
+                            .map(|(i, bar)| (i, bar.sysfs_device.as_deref()))
+                            .collect();
+                        if let Some(bar) = closest_display(&free, sysfs_device.as_deref()).map(|i| &mut bars[i]) {
+                            bar.digitizer = Some(dev.clone());
+                            let (early, others): (Vec<_>, Vec<_>) = early_touches.drain(..).partition(|(_, te)| te.device() == dev);
+                            early_touches.extend(others);
+                            if cfg.replay_early_touches {
+                                // handled next, in the order they arrived
+                                for (_, te) in early.into_iter().rev() {
+                                    events.push_front(Event::Touch(te));
+                                }
+                            } else if !early.is_empty() {
+                                counters::add(&IGNORED_TOUCHES, early.len() as u64);
+                                println!("Discarded {} touch events from before {} was set up", early.len(), dev.name());
+                            }
+                        }
+                    } else if kind == DeviceKind::Keyboard {
+                        keyboards.push(dev);
+                    }
+                },
+                Event::Device(DeviceEvent::Removed(evt)) => {
+                    let dev = evt.device();
+                    modifiers.device_removed(&dev);
+                    keyboards.retain(|kbd| *kbd != dev);
+                    if let Some((bar, layers)) = bars.iter_mut().zip(&mut layers).find(|(bar, _)| bar.digitizer.as_ref() == Some(&dev)) {
+                        bar.digitizer = None;
+                        // the device will not report these touches lifting anymore,
+                        // so their keys are released here instead of staying held
+                        bar.cancel_touches(layers, &cfg, &mut uinput, &mut stats, &mut control);
+                        bar.down_slots.clear();
                     }
                 },
                 Event::Keyboard(KeyboardEvent::Key(key)) => {
-                    if key.key() == Key::Fn as u32 {
+                    leds.request_poll();
+                    // a modifier the touchbar holds is not one the user holds, and
+                    // a button sending LayerKey must not switch the layer it is on
+                    if is_own_device(&key.device()) {
+                        continue;
+                    }
+                    modifiers.key(key.device(), key.key(), key.key_state() == KeyState::Pressed);
+                    if key.key() == cfg.layer_key as u32 {
                         let new_layer = match key.key_state() {
                             KeyState::Pressed => 1,
                             KeyState::Released => 0
                         };
-                        if active_layer != new_layer {
-                            active_layer = new_layer;
-                            needs_complete_redraw = true;
+                        layer_key_layer = new_layer;
+                        if quiet && cfg.quiet_locks_layer {
+                            continue;
+                        }
+                        for (bar, layers) in bars.iter_mut().zip(&mut layers) {
+                            if bar.active_layer != new_layer {
+                                layers[bar.shown_layer()].disarm();
+                                bar.active_layer = new_layer;
+                                bar.needs_complete_redraw = true;
+                            }
                         }
                     }
                 },
                 Event::Touch(te) => {
-                    if Some(te.device()) != digitizer || backlight.current_bl() == 0 {
+                    let dev = te.device();
+                    let Some(bar_idx) = bars.iter().position(|bar| bar.digitizer.as_ref() == Some(&dev)) else {
+                        if device_kind(&dev) == DeviceKind::Digitizer {
+                            early_touches.retain(|(at, _)| at.elapsed().as_millis() < EARLY_TOUCH_WINDOW_MS);
+                            if early_touches.len() < MAX_EARLY_TOUCHES {
+                                early_touches.push_back((Instant::now(), te));
+                            } else {
+                                counters::add(&IGNORED_TOUCHES, 1);
+                            }
+                        }
+                        continue
+                    };
+                    if backlight.current_bl() == 0 || display_released {
+                        if matches!(te, TouchEvent::Down(_)) {
+                            counters::add(&IGNORED_TOUCHES, 1);
+                        }
                         continue
                     }
+                    let bar = &mut bars[bar_idx];
+                    let layers = &mut layers[bar_idx];
+                    let (width, height) = (bar.width, bar.height);
                     match te {
                         TouchEvent::Down(dn) => {
-                            let x = dn.x_transformed(width as u32);
-                            let y = dn.y_transformed(height as u32);
-                            let btn = (x / (width as f64 / layers[active_layer].buttons.len() as f64)) as u32;
-                            if button_hit(layers[active_layer].buttons.len() as u32, btn, width, height, x, y) {
-                                touches.insert(dn.seat_slot(), (active_layer, btn));
-                                layers[active_layer].buttons[btn as usize].set_active(&cfg, &mut uinput, true);
+                            let slot = dn.seat_slot();
+                            if !bar.track_down(layers, slot, &cfg, &mut uinput, &mut stats, &mut control) {
+                                continue;
+                            }
+                            let (x, y) = flip_touch(&cfg, width, height, dn.x_transformed(width as u32), dn.y_transformed(height as u32));
+                            let layer = bar.shown_layer();
+                            let hit = layers[layer].hit(&cfg, width, height, x, y);
+                            event_log::record(Record::TouchDown { bar: bar_idx, slot, layer, button: hit });
+                            if debug_touch {
+                                println!(
+                                    "Touch down on bar {} slot {}: raw ({:.2}, {:.2}) mm, transformed ({:.1}, {:.1}), layer {} button {:?}",
+                                    bar_idx, slot, dn.x(), dn.y(), x, y, layers[layer].name, hit
+                                );
+                            }
+                            if was_dimmed && cfg.wake_swallows_touch {
+                                counters::add(&IGNORED_TOUCHES, 1);
+                                continue;
                             }
+                            if Instant::now() < bar.gesture_until {
+                                counters::add(&IGNORED_TOUCHES, 1);
+                                continue;
+                            }
+                            bar.touch_down(layers, slot, x, y, hit, &cfg, &mut uinput, &mut stats, &mut control, modifiers.state());
                         },
                         TouchEvent::Motion(mtn) => {
-                            if !touches.contains_key(&mtn.seat_slot()) {
+                            let (x, y) = flip_touch(&cfg, width, height, mtn.x_transformed(width as u32), mtn.y_transformed(height as u32));
+                            if let Some(pending) = &mut bar.pending_touch {
+                                if pending.slot == mtn.seat_slot() {
+                                    pending.x = x;
+                                    pending.y = y;
+                                    continue;
+                                }
+                            }
+                            if !bar.touches.contains_key(&mtn.seat_slot()) {
                                 continue;
                             }
 
-                            let x = mtn.x_transformed(width as u32);
-                            let y = mtn.y_transformed(height as u32);
-                            let (layer, btn) = *touches.get(&mtn.seat_slot()).unwrap();
-                            let hit = button_hit(layers[layer].buttons.len() as u32, btn, width, height, x, y);
-                            layers[layer].buttons[btn as usize].set_active(&cfg, &mut uinput, hit);
-                        },
-                        TouchEvent::Up(up) => {
-                            if !touches.contains_key(&up.seat_slot()) {
+                            let (layer, btn) = *bar.touches.get(&mtn.seat_slot()).unwrap();
+                            // sliders stay active wherever the finger goes until it is lifted
+                            if let Some(slider) = &mut layers[layer].buttons[btn].slider {
+                                let steps = slider.motion(x);
+                                if cfg.slider_coalesce_window_ms == 0 {
+                                    emit_slider_steps(&mut uinput, slider, steps);
+                                } else {
+                                    slider.queue(steps);
+                                }
                                 continue;
                             }
-                            let (layer, btn) = *touches.get(&up.seat_slot()).unwrap();
-                            layers[layer].buttons[btn as usize].set_active(&cfg, &mut uinput, false);
+                            let hit = layers[layer].button_hit(btn, &cfg, width, height, x, y);
+                            layers[layer].set_active(btn, &cfg, &mut uinput, &mut stats, &mut control, modifiers.state(), hit);
+                        },
+                        TouchEvent::Up(up) => {
+                            let slot = up.seat_slot();
+                            event_log::record(Record::TouchUp { bar: bar_idx, slot });
+                            bar.lift(layers, slot, &cfg, &mut uinput, &mut stats, &mut control, modifiers.state());
                         }
                         _ => {}
                     }
@@ -519,6 +1955,418 @@ fn real_main(drm: &mut DrmBackend) {
                 _ => {}
             }
         }
+        // a finger resting on a button sends no events, but is still using the bar.
+        // The main loop wakes up more often than the touchbar dims, so this is
+        // enough to keep it on for as long as the button is held
+        if cfg.keep_awake_while_held && bars.iter().any(|bar| !bar.touches.is_empty()) {
+            backlight.keep_awake();
+        }
         backlight.update_backlight(&cfg);
+        stats.checkpoint();
+        if state_file.enabled() && STATE_CHANGED.swap(false, Ordering::Relaxed) {
+            if let Err(e) = state_file.save(current_state(&bars, &layers)) {
+                println!("Failed to save state: {}", e);
+            }
+        }
+        control.flush_subscribers(&epoll);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::LayerIndicator;
+    use std::time::Duration;
+
+    fn key_event(value: i32) -> input_event {
+        input_event { type_: EventKind::Key as u16, code: Key::A as u16, value, time: timeval { tv_sec: 0, tv_usec: 0 } }
+    }
+
+    // Takes at most `per_write` events at a time, and nothing on the calls listed in `busy`
+    struct MockSink {
+        per_write: usize,
+        busy: Vec<usize>,
+        calls: usize,
+        written: Vec<i32>,
+    }
+
+    impl EventSink for MockSink {
+        fn write(&mut self, events: &[input_event]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.busy.contains(&self.calls) {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            let n = events.len().min(self.per_write);
+            self.written.extend(events[..n].iter().map(|event| event.value));
+            Ok(n)
+        }
+    }
+
+    // Keeps the value of every key event
+    #[derive(Default)]
+    struct KeyLog(Vec<i32>);
+
+    impl EventSink for KeyLog {
+        fn write(&mut self, events: &[input_event]) -> io::Result<usize> {
+            self.0.extend(events.iter().filter(|event| event.type_ == EventKind::Key as u16).map(|event| event.value));
+            Ok(events.len())
+        }
+    }
+
+    // Keeps the code and value of every key event
+    #[derive(Default)]
+    struct KeyCodes(Vec<(u16, i32)>);
+
+    impl EventSink for KeyCodes {
+        fn write(&mut self, events: &[input_event]) -> io::Result<usize> {
+            self.0.extend(events.iter().filter(|event| event.type_ == EventKind::Key as u16).map(|event| (event.code, event.value)));
+            Ok(events.len())
+        }
+    }
+
+    // A finger wiggling on the edge of a button, with a Motion event every 100us
+    // like the main loop handles them. Every time it crosses the edge is sent,
+    // while frames are only drawn once per FrameInterval.
+    #[test]
+    fn motion_storm_sends_every_key_and_bounded_frames() {
+        let mut cfg = config::test_config();
+        cfg.edge_to_edge = false;
+        cfg.frame_interval_ms = 16;
+        let (width, height) = (2008, 60);
+        let mut layer = text_layer(&[None; 4]);
+        layer.buttons[0].repeat = Some(true);
+        let edge = layer.edges(&cfg, width)[0].1;
+        let mut keys = KeyLog::default();
+        layer.buttons[0].set_active(&cfg, &mut keys, Modifiers::default(), true);
+        let mut expected = vec![1];
+        let start = Instant::now();
+        let mut last_frame = start;
+        let mut frames = 0;
+        let events = 5000;
+        for i in 0..events {
+            let now = start + Duration::from_micros(100 * i);
+            let x = if i % 3 == 0 { edge + 0.5 } else { edge - 0.5 };
+            let hit = layer.button_hit(0, &cfg, width, height, x, 30.0);
+            if layer.buttons[0].set_active(&cfg, &mut keys, Modifiers::default(), hit) {
+                expected.push(hit as i32);
+            }
+            if layer.buttons.iter().any(|b| b.needs_redraw(&cfg)) && frame_wait_ms(last_frame, now, cfg.frame_interval_ms).is_none() {
+                layer.scene(&cfg, width, (0.0, 0.0), &[], false);
+                last_frame = now;
+                frames += 1;
+            }
+        }
+        // it is let go of on every third event and pressed again on the next one
+        let outside = (0..events).filter(|i| i % 3 == 0).count();
+        assert_eq!(expected.len(), 1 + 2 * outside);
+        assert_eq!(keys.0, expected);
+        let duration_ms = events as u32 / 10;
+        assert!(frames > 0 && frames <= duration_ms / cfg.frame_interval_ms + 1, "{} frames", frames);
+    }
+
+    #[test]
+    fn write_events_retries_short_writes_and_eagain() {
+        let mut sink = MockSink { per_write: 1, busy: vec![2, 3], calls: 0, written: Vec::new() };
+        let events: Vec<input_event> = (0..4).map(key_event).collect();
+        write_events(&mut sink, &events).unwrap();
+        assert_eq!(sink.written, vec![0, 1, 2, 3]);
+        assert_eq!(sink.calls, 6);
+    }
+
+    #[test]
+    fn write_events_gives_up_without_progress() {
+        let mut sink = MockSink { per_write: 0, busy: Vec::new(), calls: 0, written: Vec::new() };
+        let err = write_events(&mut sink, &[key_event(1)]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+        assert_eq!(sink.calls, UINPUT_WRITE_ATTEMPTS);
+        let mut sink = MockSink { per_write: 1, busy: (1..=UINPUT_WRITE_ATTEMPTS).collect(), calls: 0, written: Vec::new() };
+        assert!(write_events(&mut sink, &[key_event(1)]).is_err());
+        assert!(sink.written.is_empty());
+    }
+
+    fn text_layer(groups: &[Option<&str>]) -> FunctionLayer {
+        let buttons = groups.iter().enumerate().map(|(i, group)| {
+            let mut button = Button::new_text(format!("F{}", i + 1), Action::Key(Key::F1));
+            button.group = group.map(str::to_string);
+            button
+        }).collect();
+        FunctionLayer { name: "test".to_string(), buttons }
+    }
+
+    // Every pixel of a button hits it, for each offset of the pixel shift
+    fn assert_hits_match_drawing(layer: &mut FunctionLayer, cfg: &Config, width: u16, height: u16) {
+        let edges = layer.edges(cfg, width);
+        let y = height as f64 / 2.0;
+        for shift in [-11.0, 0.0, 5.5, 11.0] {
+            let scene = layer.scene(cfg, width, (shift, 0.0), &[], true);
+            for (i, button) in scene.iter().enumerate() {
+                assert_eq!((button.left_edge, button.right_edge), (edges[i].0 + shift, edges[i].1 + shift));
+            }
+        }
+        for x in 0..width {
+            let x = x as f64;
+            let drawn = edges.iter().position(|&(left, right)| x >= left && x < right);
+            assert_eq!(layer.hit(cfg, width, height, x, y), drawn, "at {}", x);
+        }
+    }
+
+    #[test]
+    fn edge_to_edge_seams_belong_to_one_button() {
+        let mut cfg = config::test_config();
+        cfg.edge_to_edge = true;
+        cfg.layer_indicator = LayerIndicator::None;
+        let (width, height) = (2008, 60);
+        // the widths of the buttons are not whole pixels
+        let mut layer = text_layer(&[None; 7]);
+        for enable_pixel_shift in [false, true] {
+            cfg.enable_pixel_shift = enable_pixel_shift;
+            let offset = if enable_pixel_shift { (PIXEL_SHIFT_WIDTH_PX / 2) as f64 } else { 0.0 };
+            let edges = layer.edges(&cfg, width);
+            assert_eq!(edges[0].0, offset);
+            assert_eq!(edges[6].1, width as f64 - offset);
+            for pair in edges.windows(2) {
+                let seam = pair[0].1;
+                assert_eq!(seam, pair[1].0);
+                let btn = layer.hit(&cfg, width, height, seam - 0.01, 30.0).unwrap();
+                assert_eq!(layer.hit(&cfg, width, height, seam, 30.0), Some(btn + 1));
+            }
+            assert_hits_match_drawing(&mut layer, &cfg, width, height);
+        }
+    }
+
+    #[test]
+    fn gaps_in_a_group_hit_nothing() {
+        let mut cfg = config::test_config();
+        cfg.edge_to_edge = false;
+        cfg.layer_indicator = LayerIndicator::None;
+        let (width, height) = (2008, 60);
+        let mut layer = text_layer(&[None, Some("volume"), Some("volume"), Some("volume"), None]);
+        for enable_pixel_shift in [false, true] {
+            cfg.enable_pixel_shift = enable_pixel_shift;
+            let edges = layer.edges(&cfg, width);
+            for (i, pair) in edges.windows(2).enumerate() {
+                let gap = pair[1].0 - pair[0].1;
+                let joined = layer.in_group_with(i, i + 1);
+                assert!(gap > 0.0 && gap <= if joined { GROUP_SPACING_PX + 1 } else { BUTTON_SPACING_PX + 1 } as f64);
+                assert_eq!(layer.hit(&cfg, width, height, pair[0].1 - 0.5, 30.0), Some(i));
+                assert_eq!(layer.hit(&cfg, width, height, (pair[0].1 + pair[1].0) / 2.0, 30.0), None);
+                assert_eq!(layer.hit(&cfg, width, height, pair[1].0, 30.0), Some(i + 1));
+            }
+            // the inner corners of the group are square
+            let scene = layer.scene(&cfg, width, (0.0, 0.0), &[], true);
+            let corners: Vec<(bool, bool)> = scene.iter().map(|b| (b.round_left, b.round_right)).collect();
+            assert_eq!(corners, vec![(true, true), (true, false), (false, false), (false, true), (true, true)]);
+            assert_hits_match_drawing(&mut layer, &cfg, width, height);
+        }
+        // above and below the buttons
+        assert_eq!(layer.hit(&cfg, width, height, 100.0, 3.0), None);
+        assert_eq!(layer.hit(&cfg, width, height, 100.0, 57.0), None);
+    }
+
+    #[test]
+    fn touches_over_the_limit_are_ignored() {
+        let max_touches = config::test_config().max_touches;
+        let ignored_before = IGNORED_TOUCHES.load(Ordering::Relaxed);
+        let mut down_slots = HashSet::new();
+        let tracked: Vec<bool> = (0..11).map(|slot| track_touch(&mut down_slots, slot, max_touches)).collect();
+        assert_eq!(tracked.iter().filter(|&&t| t).count(), max_touches);
+        assert!(tracked[..max_touches].iter().all(|&t| t));
+        assert_eq!(down_slots, (0..max_touches as u32).collect());
+        // other tests can count ignored touches at the same time
+        assert!(IGNORED_TOUCHES.load(Ordering::Relaxed) - ignored_before >= 11 - max_touches as u64);
+        // once one is lifted there is room for another
+        down_slots.remove(&0);
+        assert!(track_touch(&mut down_slots, 11, max_touches));
+        assert!(!track_touch(&mut down_slots, 12, max_touches));
+    }
+
+    // A whole hand on the bar at once with ExtraTouches = "CancelAll", each
+    // finger on a button of its own, as the main loop handles touching down
+    #[test]
+    fn eleven_fingers_cancel_all_and_recover() {
+        let mut cfg = config::test_config();
+        cfg.extra_touches = ExtraTouches::CancelAll;
+        let (width, height) = (2008, 60);
+        let mut layers = vec![text_layer(&[None; 12]), text_layer(&[None; 12])];
+        let centers: Vec<f64> = layers[0].layout(&cfg, width as f64).iter().map(|&(left, w)| left + w / 2.0).collect();
+        let mut bar = Bar::with_screen((), width, height, None);
+        let mut sink = MockSink { per_write: usize::MAX, busy: Vec::new(), calls: 0, written: Vec::new() };
+        let mut stats = UsageStats::new(None, None);
+        let mut control = ControlServer::for_tests();
+        for slot in 0..11 {
+            if bar.track_down(&mut layers, slot, &cfg, &mut sink, &mut stats, &mut control) {
+                bar.press(&mut layers, slot, centers[slot as usize], 30.0, &cfg, &mut sink, &mut stats, &mut control, Modifiers::default());
+            }
+        }
+        // every key and sync event has a value of 1 only for a press
+        let presses = sink.written.iter().filter(|&&value| value == 1).count();
+        assert_eq!(presses, cfg.max_touches);
+        assert_eq!(sink.written.len(), 4 * cfg.max_touches);
+        assert!(bar.touches.is_empty());
+        assert!(layers[0].buttons.iter().all(|button| !button.active));
+        // the fingers still down do nothing when lifted
+        for slot in 0..11 {
+            bar.lift(&mut layers, slot, &cfg, &mut sink, &mut stats, &mut control, Modifiers::default());
+        }
+        assert_eq!(sink.written.len(), 4 * cfg.max_touches);
+        assert!(bar.down_slots.is_empty());
+        // and the next touch works as usual
+        assert!(bar.track_down(&mut layers, 0, &cfg, &mut sink, &mut stats, &mut control));
+        bar.press(&mut layers, 0, centers[11], 30.0, &cfg, &mut sink, &mut stats, &mut control, Modifiers::default());
+        assert!(layers[0].buttons[11].active);
+        bar.lift(&mut layers, 0, &cfg, &mut sink, &mut stats, &mut control, Modifiers::default());
+        assert_eq!(sink.written[4 * cfg.max_touches..], [1, 0, 0, 0]);
+    }
+
+    // Two fingers landing on a button with a TwoFingerAction within the peek
+    // window, as the main loop handles touching down and lifting
+    #[test]
+    fn two_fingers_send_the_two_finger_action_only() {
+        let cfg = config::test_config();
+        let (width, height) = (2008, 60);
+        let mut layers = vec![text_layer(&[None; 4]), text_layer(&[None; 4])];
+        layers[0].buttons[1].two_finger_action = Some(Action::Key(Key::F2));
+        let (left, button_width) = layers[0].layout(&cfg, width as f64)[1];
+        let mut bar = Bar::with_screen((), width, height, None);
+        let mut keys = KeyCodes::default();
+        let mut stats = UsageStats::new(None, None);
+        let mut control = ControlServer::for_tests();
+        for (slot, x) in [(0, left + button_width / 3.0), (1, left + button_width * 2.0 / 3.0)] {
+            let hit = layers[0].hit(&cfg, width, height, x, 30.0);
+            assert_eq!(hit, Some(1));
+            assert!(bar.track_down(&mut layers, slot, &cfg, &mut keys, &mut stats, &mut control));
+            bar.touch_down(&mut layers, slot, x, 30.0, hit, &cfg, &mut keys, &mut stats, &mut control, Modifiers::default());
+        }
+        // the first finger pressed nothing on its own
+        assert_eq!(keys.0, [(Key::F2 as u16, 1)]);
+        bar.lift(&mut layers, 0, &cfg, &mut keys, &mut stats, &mut control, Modifiers::default());
+        assert_eq!(keys.0.len(), 1);
+        bar.lift(&mut layers, 1, &cfg, &mut keys, &mut stats, &mut control, Modifiers::default());
+        assert_eq!(keys.0, [(Key::F2 as u16, 1), (Key::F2 as u16, 0)]);
+        // a quick tap with one finger is the usual action
+        let x = left + button_width / 2.0;
+        assert!(bar.track_down(&mut layers, 2, &cfg, &mut keys, &mut stats, &mut control));
+        bar.touch_down(&mut layers, 2, x, 30.0, Some(1), &cfg, &mut keys, &mut stats, &mut control, Modifiers::default());
+        bar.lift(&mut layers, 2, &cfg, &mut keys, &mut stats, &mut control, Modifiers::default());
+        assert_eq!(keys.0[2..], [(Key::F1 as u16, 1), (Key::F1 as u16, 0)]);
+    }
+
+    #[test]
+    fn button_held_by_two_slots_releases_when_both_lift() {
+        let mut touches = HashMap::from([(0, (0, 2)), (1, (0, 2)), (2, (0, 3))]);
+        assert_eq!(lift_touch(&mut touches, 0), None);
+        assert_eq!(lift_touch(&mut touches, 2), Some((0, 3)));
+        assert_eq!(lift_touch(&mut touches, 1), Some((0, 2)));
+        // slots that were never tracked, like ones over MaxTouches
+        assert_eq!(lift_touch(&mut touches, 1), None);
+        assert_eq!(lift_touch(&mut touches, 7), None);
+        assert!(touches.is_empty());
+    }
+
+    #[test]
+    fn confirm_holds_back_what_the_touchbar_handles() {
+        let cfg = config::test_config();
+        for action in [Action::Quiet, Action::Layer("numpad".to_string()), Action::DumpState] {
+            let mut button = Button::new_text("Sure".to_string(), action.clone());
+            button.confirm = true;
+            let mut keys = KeyLog::default();
+            // the arming tap does nothing yet
+            button.set_active(&cfg, &mut keys, Modifiers::default(), true);
+            assert!(button.armed_at.is_some());
+            assert!(button.pressed_action.is_none());
+            button.set_active(&cfg, &mut keys, Modifiers::default(), false);
+            button.set_active(&cfg, &mut keys, Modifiers::default(), true);
+            assert_eq!(button.pressed_action.as_ref().map(Action::kind), Some(action.kind()));
+            button.set_active(&cfg, &mut keys, Modifiers::default(), false);
+            assert!(button.pressed_action.is_none());
+            assert!(keys.0.is_empty());
+        }
+    }
+
+    #[test]
+    fn wheel_is_only_used_with_a_scroll_button() {
+        let mut layers = vec![vec![text_layer(&[None; 3])]];
+        assert!(!uses_wheel(&layers));
+        layers[0][0].buttons[2].shift_action = Some(Action::Scroll(-1));
+        assert!(uses_wheel(&layers));
+    }
+
+    #[test]
+    fn only_listed_keys_repeat() {
+        let mut cfg = config::test_config();
+        cfg.repeat_keys = Some(vec![Key::VolumeUp]);
+        let mut button = Button::new_text("Vol".to_string(), Action::Key(Key::VolumeUp));
+        assert!(button.repeats(&cfg, Key::VolumeUp));
+        assert!(!button.repeats(&cfg, Key::Mute));
+        // the setting of the button wins over the list, both ways
+        button.repeat = Some(true);
+        assert!(button.repeats(&cfg, Key::Mute));
+        button.repeat = Some(false);
+        assert!(!button.repeats(&cfg, Key::VolumeUp));
+        // without a list every key repeats
+        cfg.repeat_keys = None;
+        button.repeat = None;
+        assert!(button.repeats(&cfg, Key::Mute));
+    }
+
+    // A touch lands on what is drawn under it, for every combination of the
+    // flips with the rotation of the display
+    #[test]
+    fn flipped_touches_match_the_drawing() {
+        let (width, height) = (2008u16, 60u16);
+        let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, height as i32, width as i32).unwrap();
+        let mut cfg = config::test_config();
+        for (flip_horizontal, flip_vertical) in [(false, false), (true, false), (false, true), (true, true)] {
+            cfg.flip_horizontal = flip_horizontal;
+            cfg.flip_vertical = flip_vertical;
+            let c = cairo::Context::new(&surface).unwrap();
+            render::transform_to_bar(&c, &Style::from_config(&cfg), width as i32, height as i32);
+            for (x, y) in [(0.0, 0.0), (100.5, 10.0), (1500.0, 30.25), (2008.0, 60.0)] {
+                // the digitizer is not rotated, its x goes along the framebuffer rows
+                let (dev_x, dev_y) = c.user_to_device(x, y);
+                let touch = (dev_y, height as f64 - dev_x);
+                let (tx, ty) = flip_touch(&cfg, width, height, touch.0, touch.1);
+                assert!(
+                    (tx - x).abs() < 1e-9 && (ty - y).abs() < 1e-9,
+                    "flips {:?}: drawn at ({}, {}), touched at ({}, {})",
+                    (flip_horizontal, flip_vertical), x, y, tx, ty
+                );
+            }
+        }
+    }
+
+    // Creates the real virtual device and lets libinput look at it, with
+    // AdvertiseKeyboard it has every key of a keyboard. Needs /dev/uinput.
+    #[test]
+    fn own_device_is_neither_a_keyboard_nor_a_digitizer() {
+        for advertise_keyboard in [false, true] {
+            let Ok(file) = OpenOptions::new().write(true).open("/dev/uinput") else {
+                println!("Skipping, /dev/uinput can not be opened");
+                return;
+            };
+            let mut uinput = UInputHandle::new(file);
+            setup_uinput_device(&mut uinput, &[], advertise_keyboard).unwrap();
+            let path = uinput.evdev_path().unwrap();
+            let mut input = Libinput::new_from_path(Interface);
+            let dev = input.path_add_device(path.to_str().unwrap()).unwrap();
+            assert!(is_own_device(&dev));
+            assert_eq!(device_kind(&dev), DeviceKind::Other);
+            _ = uinput.dev_destroy();
+        }
+    }
+
+    #[test]
+    fn digitizers_pair_with_the_display_of_their_usb_device() {
+        let first = Path::new("/sys/devices/pci0000:00/usb1/1-1/1-1:1.0/drm/card0");
+        let second = Path::new("/sys/devices/pci0000:00/usb2/2-1/2-1:1.0/drm/card1");
+        let free = [(0, Some(first)), (1, Some(second))];
+        // the digitizer of the second display shows up first
+        let digitizer = Path::new("/sys/devices/pci0000:00/usb2/2-1/2-1:1.2/input/input7/event7");
+        assert_eq!(closest_display(&free, Some(digitizer)), Some(1));
+        let digitizer = Path::new("/sys/devices/pci0000:00/usb1/1-1/1-1:1.2/input/input5/event5");
+        assert_eq!(closest_display(&free[..1], Some(digitizer)), Some(0));
+        // without sysfs paths they go by order
+        assert_eq!(closest_display(&[(0, None), (1, None)], None), Some(0));
+        assert_eq!(closest_display(&[], Some(digitizer)), None);
     }
 }
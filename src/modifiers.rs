@@ -0,0 +1,52 @@
+use input::Device as InputDevice;
+use input_linux::Key;
+
+#[derive(Clone, Copy, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+const SHIFT_KEYS: [Key; 2] = [Key::LeftShift, Key::RightShift];
+const CTRL_KEYS: [Key; 2] = [Key::LeftCtrl, Key::RightCtrl];
+const ALT_KEYS: [Key; 2] = [Key::LeftAlt, Key::RightAlt];
+
+fn is_one_of(key: u32, keys: &[Key]) -> bool {
+    keys.iter().any(|k| *k as u32 == key)
+}
+
+// Modifier keys held on the physical keyboards. The device is remembered
+// with every key, so that keys held while a keyboard is unplugged do not
+// stay pressed forever.
+pub struct ModifierTracker {
+    held: Vec<(InputDevice, u32)>,
+}
+
+impl ModifierTracker {
+    pub fn new() -> ModifierTracker {
+        ModifierTracker {
+            held: Vec::new(),
+        }
+    }
+    pub fn key(&mut self, device: InputDevice, key: u32, pressed: bool) {
+        if ![SHIFT_KEYS, CTRL_KEYS, ALT_KEYS].iter().any(|keys| is_one_of(key, keys)) {
+            return;
+        }
+        self.held.retain(|(d, k)| *d != device || *k != key);
+        if pressed {
+            self.held.push((device, key));
+        }
+    }
+    pub fn device_removed(&mut self, device: &InputDevice) {
+        self.held.retain(|(d, _)| d != device);
+    }
+    pub fn state(&self) -> Modifiers {
+        let held = |keys: &[Key]| self.held.iter().any(|(_, key)| is_one_of(*key, keys));
+        Modifiers {
+            shift: held(&SHIFT_KEYS),
+            ctrl: held(&CTRL_KEYS),
+            alt: held(&ALT_KEYS),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::Mutex;
+use anyhow::Result;
+use drm::control::ClipRect;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
+use crate::counters::{self, COMMITS, FAILED_COMMITS};
+use crate::display::DrmBackend;
+use crate::event_log::{self, Record};
+
+// A frame is not waited for longer than this, in case the vblank event
+// gets lost, eg. because the display was turned off in the meantime
+const VBLANK_TIMEOUT_MS: isize = 100;
+
+pub struct Frame {
+    pub data: Vec<u8>,
+    pub clips: Vec<ClipRect>,
+}
+
+// Waits for the vblank events of one render thread
+pub fn vblank_epoll() -> Epoll {
+    Epoll::new(EpollCreateFlags::empty()).unwrap()
+}
+
+// Sends a finished frame to the display, on the render thread of the bar.
+// The display is shared with the main loop, which checks and rebuilds it.
+pub fn show(display: &Mutex<DrmBackend>, frame: &Frame, bar: usize, epoll: &Epoll) -> Result<()> {
+    let mut drm = display.lock().unwrap();
+    // the main loop redraws everything once the display is taken back
+    if drm.is_released() {
+        return Ok(());
+    }
+    counters::add(&COMMITS, 1);
+    if let Err(e) = copy(&mut drm, frame) {
+        println!("Failed to update display: {}", e);
+        counters::add(&FAILED_COMMITS, 1);
+        event_log::record(Record::FrameFailed { bar });
+        return Err(e);
+    }
+    event_log::record(Record::FrameShown { bar, clips: frame.clips.len() });
+    if !drm.request_vblank() {
+        return Ok(());
+    }
+    // at most one frame is in flight, the next one waits for the display
+    // to pick this one up. The display is not kept locked meanwhile,
+    // if the main loop rebuilds it the wait just runs into the timeout.
+    // Closing the old device also takes its fd out of the epoll.
+    if epoll.add(drm.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 0)).is_err() {
+        return Ok(());
+    }
+    drop(drm);
+    _ = epoll.wait(&mut [EpollEvent::empty()], VBLANK_TIMEOUT_MS);
+    let drm = display.lock().unwrap();
+    _ = epoll.delete(drm.fd());
+    drm.receive_vblank();
+    Ok(())
+}
+
+fn copy(drm: &mut DrmBackend, frame: &Frame) -> Result<()> {
+    drm.map()?.as_mut()[..frame.data.len()].copy_from_slice(&frame.data);
+    drm.dirty(&frame.clips)
+}
@@ -0,0 +1,797 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Write},
+    os::fd::AsFd,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError}},
+    thread::Scope,
+    time::{Duration, Instant},
+};
+use anyhow::{Error, Result, anyhow};
+use cairo::{Antialias, Context, FontFace, Format, ImageSurface, LinearGradient, Rectangle};
+use drm::control::ClipRect;
+use nix::sys::{
+    eventfd::{eventfd, EfdFlags},
+    signal::{SigSet, Signal},
+};
+use rsvg::{CairoRenderer, Loader, SvgHandle};
+use crate::config::{self, ActiveEffect, ButtonStyle, Config, IndicatorPosition, LayerIndicator};
+use crate::counters::{self, FRAMES};
+use crate::display::DrmBackend;
+use crate::present::{self, Frame};
+use crate::BUTTON_SPACING_PX;
+
+const ICON_SIZE: i32 = 48;
+const FONT_SIZE: f64 = 32.0;
+// space kept free on both sides of a label
+const TEXT_PADDING_PX: f64 = 8.0;
+const ACTIVE_BORDER_WIDTH_PX: f64 = 2.0;
+const ACTIVE_GLOW_WIDTH_PX: i32 = 6;
+const ACTIVE_INSET_DEPTH_PX: f64 = 14.0;
+const ACTIVE_INSET_ALPHA: f64 = 0.6;
+// the progress line is drawn this far above the bottom edge of the box
+const HOLD_PROGRESS_OFFSET_PX: f64 = 6.0;
+const HOLD_PROGRESS_WIDTH_PX: f64 = 2.0;
+const INDICATOR_DOT_RADIUS_PX: f64 = 3.0;
+const INDICATOR_DOT_SPACING_PX: f64 = 10.0;
+// frames of a layer fade are drawn at this rate
+const FADE_FRAME_MS: u64 = 1000 / 30;
+
+// Where the image of an icon button comes from. Loaded images can not be
+// sent to other threads, so the render threads load it again from this.
+pub enum IconSource {
+    Svg(PathBuf),
+    Png(PathBuf),
+}
+
+enum LoadedIcon {
+    Svg(SvgHandle),
+    Bitmap(ImageSurface),
+}
+
+fn load_svg(path: &Path) -> Result<LoadedIcon> {
+    Ok(LoadedIcon::Svg(Loader::new().read_path(path)?))
+}
+
+fn load_png(path: &Path) -> Result<LoadedIcon> {
+    let surf = ImageSurface::create_from_png(&mut File::open(path)?)?;
+    if surf.height() == ICON_SIZE && surf.width() == ICON_SIZE {
+        return Ok(LoadedIcon::Bitmap(surf));
+    }
+    let resized = ImageSurface::create(Format::ARgb32, ICON_SIZE, ICON_SIZE).unwrap();
+    let c = Context::new(&resized).unwrap();
+    c.scale(ICON_SIZE as f64 / surf.width() as f64, ICON_SIZE as f64 / surf.height() as f64);
+    c.set_source_surface(surf, 0.0, 0.0).unwrap();
+    c.set_antialias(Antialias::Best);
+    c.paint().unwrap();
+    drop(c);
+    Ok(LoadedIcon::Bitmap(resized))
+}
+
+impl IconSource {
+    // Icons are looked up in /etc/tiny-dfr first and then in /usr/share/tiny-dfr.
+    // They are loaded once here, so that a broken one fails the config.
+    pub fn find(name: &str) -> Result<IconSource> {
+        let path = |dir: &str, ext: &str| PathBuf::from(format!("{}/{}.{}", dir, name, ext));
+        let svg = |dir| {
+            let path = path(dir, "svg");
+            load_svg(&path).map(|_| IconSource::Svg(path))
+        };
+        let png = |dir| {
+            let path = path(dir, "png");
+            load_png(&path).map(|_| IconSource::Png(path))
+        };
+        svg("/etc/tiny-dfr")
+            .or_else(|_| svg("/usr/share/tiny-dfr"))
+            .or_else(|_| png("/etc/tiny-dfr"))
+            .or_else(|_| png("/usr/share/tiny-dfr"))
+    }
+    fn path(&self) -> &Path {
+        match self {
+            IconSource::Svg(path) | IconSource::Png(path) => path,
+        }
+    }
+    fn load(&self) -> Result<LoadedIcon> {
+        match self {
+            IconSource::Svg(path) => load_svg(path),
+            IconSource::Png(path) => load_png(path),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Image {
+    Text(String),
+    Icon(Arc<IconSource>),
+}
+
+// The part of the config that drawing needs. Unlike the config it can be
+// sent to the render threads, which load the font from its name themselves.
+pub struct Style {
+    pub font_template: String,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub edge_to_edge: bool,
+    pub divider_width: u32,
+    pub button_style: ButtonStyle,
+    pub layer_indicator: LayerIndicator,
+    pub layer_indicator_position: IndicatorPosition,
+    pub layer_fade_ms: u32,
+}
+
+impl Style {
+    pub fn from_config(config: &Config) -> Style {
+        Style {
+            font_template: config.font_template.clone(),
+            flip_horizontal: config.flip_horizontal,
+            flip_vertical: config.flip_vertical,
+            edge_to_edge: config.edge_to_edge,
+            divider_width: config.divider_width,
+            button_style: config.button_style,
+            layer_indicator: config.layer_indicator,
+            layer_indicator_position: config.layer_indicator_position,
+            layer_fade_ms: config.layer_fade_ms,
+        }
+    }
+}
+
+// A shown button as it is to be drawn, with the layout and pixel shift applied
+#[derive(Clone)]
+pub struct ButtonScene {
+    pub image: Image,
+    // icons are drawn in this color, using only their transparency
+    pub icon_color: Option<(f64, f64, f64)>,
+    pub left_edge: f64,
+    pub width: f64,
+    // where the box ends, which for buttons that touch is where the next one starts
+    pub right_edge: f64,
+    pub round_left: bool,
+    pub round_right: bool,
+    pub color: (f64, f64, f64),
+    pub level: f64,
+    pub progress: f64,
+    // only changed buttons are drawn, unless the whole scene is
+    pub changed: bool,
+}
+
+// Everything that is drawn on a touchbar, as plain data taken on the main loop
+pub struct Scene {
+    pub style: Arc<Style>,
+    pub layer: usize,
+    pub layer_name: String,
+    // index of the layer among all of them and their count
+    pub position: (usize, usize),
+    pub buttons: Vec<ButtonScene>,
+    // left edge and width of the area the buttons are laid out in
+    pub area: (f64, f64),
+    pub y_shift: f64,
+    pub complete: bool,
+    // a message and the left edge and width of the area it is centered in
+    pub toast: Option<(String, (f64, f64))>,
+}
+
+// The last frame as it was drawn, for state dumps
+pub struct Snapshot {
+    pub width: i32,
+    pub height: i32,
+    pub stride: i32,
+    pub data: Vec<u8>,
+}
+
+enum Request {
+    Scene(Scene),
+    Snapshot(Sender<Snapshot>),
+}
+
+// Draws the scenes taken on the main loop and sends them to the display on a
+// thread of its own, as drawing icons and text and updating the display can
+// take a while, eg. for displays attached over USB, which would otherwise hold
+// up handling touches and sending keys on the main loop. The cairo surface,
+// the font and the icons belong to the thread. The display is shared with
+// the main loop, which checks and rebuilds it.
+// While a scene is being drawn and shown the renderer is busy and the main
+// loop holds back the next one, which is woken up through an eventfd once
+// the display took the frame.
+pub struct Renderer {
+    requests: Sender<Request>,
+    errors: Receiver<Failure>,
+    busy: Arc<AtomicBool>,
+    done: Arc<File>,
+    // a snapshot that was asked for and not answered yet
+    snapshot: Option<Receiver<Snapshot>>,
+}
+
+impl Renderer {
+    // The thread ends once the Renderer is dropped, and is joined along with the scope
+    pub fn spawn<'scope, 'env>(scope: &'scope Scope<'scope, 'env>, drm: &'env Mutex<DrmBackend>, bar: usize) -> Renderer {
+        let (requests, requests_rx) = mpsc::channel();
+        let (errors_tx, errors) = mpsc::channel();
+        let busy = Arc::new(AtomicBool::new(false));
+        let done = Arc::new(File::from(eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC).unwrap()));
+        let shown = Shown { busy: busy.clone(), done: done.clone() };
+        scope.spawn(move || render(drm, requests_rx, errors_tx, shown, bar));
+        Renderer { requests, errors, busy, done, snapshot: None }
+    }
+    pub fn send(&self, scene: Scene) {
+        self.busy.store(true, Ordering::Release);
+        _ = self.requests.send(Request::Scene(scene));
+    }
+    pub fn is_busy(&self) -> bool {
+        self.busy.load(Ordering::Acquire)
+    }
+    // Readable once a scene was shown or a snapshot was taken
+    pub fn fd(&self) -> impl AsFd + '_ {
+        self.done.as_fd()
+    }
+    // Needs to be called after every wakeup, the eventfd stays readable until it is read
+    pub fn clear_shown(&self) {
+        _ = (&*self.done).read(&mut [0u8; 8]);
+    }
+    // What went wrong on the render thread since the last call
+    pub fn failures(&self) -> Vec<Failure> {
+        self.errors.try_iter().collect()
+    }
+    // Asks for the frame once the scenes sent so far are drawn, see take_snapshot
+    pub fn request_snapshot(&mut self) {
+        if self.snapshot.is_some() {
+            return;
+        }
+        let (reply, snapshot) = mpsc::channel();
+        _ = self.requests.send(Request::Snapshot(reply));
+        self.snapshot = Some(snapshot);
+    }
+    // The snapshot asked for, once the render thread took it
+    pub fn take_snapshot(&mut self) -> Option<Result<Snapshot>> {
+        let result = match self.snapshot.as_ref()?.try_recv() {
+            Ok(snapshot) => Ok(snapshot),
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(anyhow!("Render thread did not respond")),
+        };
+        self.snapshot = None;
+        Some(result)
+    }
+}
+
+// The render thread side of the busy flag
+struct Shown {
+    busy: Arc<AtomicBool>,
+    done: Arc<File>,
+}
+
+impl Shown {
+    fn notify(&self) {
+        if self.busy.swap(false, Ordering::AcqRel) {
+            self.wake();
+        }
+    }
+    fn wake(&self) {
+        _ = (&*self.done).write(&1u64.to_ne_bytes());
+    }
+}
+
+// The last frame of the previous layer, blended over the new one while it fades out
+struct LayerFade {
+    from: ImageSurface,
+    started: Instant,
+    duration_ms: u32,
+}
+
+pub enum Failure {
+    // a frame did not reach the display
+    Frame(Error),
+    // the FontTemplate of a reloaded config, the last font that loaded is used instead
+    Font(Error),
+}
+
+struct Resources {
+    // the template last asked for, and the last font that loaded
+    font_template: Option<String>,
+    font: Option<FontFace>,
+    icons: HashMap<PathBuf, Option<LoadedIcon>>,
+    errors: Sender<Failure>,
+}
+
+impl Resources {
+    // None only if no font loaded at all, then cairo picks its own
+    fn font(&mut self, template: &str) -> Option<FontFace> {
+        if self.font_template.as_deref() != Some(template) {
+            self.font_template = Some(template.to_string());
+            // the main loop loaded it once when reading the config, but the font may be gone by now
+            match config::load_font(template) {
+                Ok(font) => self.font = Some(font),
+                Err(e) => _ = self.errors.send(Failure::Font(e)),
+            }
+        }
+        self.font.clone()
+    }
+    fn icon(&mut self, source: &IconSource) -> Option<&LoadedIcon> {
+        self.icons.entry(source.path().to_path_buf()).or_insert_with(|| {
+            source.load().map_err(|e| println!("Failed to load icon {}: {}", source.path().display(), e)).ok()
+        }).as_ref()
+    }
+}
+
+fn render(display: &Mutex<DrmBackend>, requests: Receiver<Request>, errors: Sender<Failure>, shown: Shown, bar: usize) {
+    // the thread is started before the main loop blocks these to read them from a signalfd
+    let mut sigset = SigSet::empty();
+    sigset.add(Signal::SIGTERM);
+    sigset.add(Signal::SIGINT);
+    sigset.thread_block().unwrap();
+    let drm = display.lock().unwrap();
+    let (height, width) = drm.mode().size();
+    let (db_width, db_height) = drm.fb_info().unwrap().size();
+    drop(drm);
+    let mut surface = ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
+    let mut resources = Resources { font_template: None, font: None, icons: HashMap::new(), errors: errors.clone() };
+    let mut drawn_layer = None;
+    let mut fade: Option<LayerFade> = None;
+    let epoll = present::vblank_epoll();
+    loop {
+        // while fading, frames are shown without waiting for a new scene
+        let first = match &fade {
+            Some(_) => match requests.recv_timeout(Duration::from_millis(FADE_FRAME_MS)) {
+                Ok(request) => Some(request),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match requests.recv() {
+                Ok(request) => Some(request),
+                Err(_) => return,
+            },
+        };
+        // the main loop holds the next scene back until this one is shown,
+        // so at most one is queued
+        let mut scene: Option<Scene> = None;
+        let mut snapshots = Vec::new();
+        for request in first.into_iter().chain(requests.try_iter()) {
+            match request {
+                Request::Scene(newer) => scene = Some(newer),
+                Request::Snapshot(reply) => snapshots.push(reply),
+            }
+        }
+        let mut clips = Vec::new();
+        if let Some(scene) = &scene {
+            // the surface still holds the last frame of the previous layer
+            if drawn_layer.is_some_and(|layer| layer != scene.layer) && scene.style.layer_fade_ms > 0 {
+                fade = Some(LayerFade { from: copy_surface(&surface), started: Instant::now(), duration_ms: scene.style.layer_fade_ms });
+            }
+            drawn_layer = Some(scene.layer);
+            // every frame of the fade is a complete one, as the whole layer changes
+            clips = draw(scene, &surface, &mut resources, width as i32, height as i32, fade.is_some());
+        }
+        // answered once the scenes sent before are drawn, the main loop
+        // picks them up when woken
+        let answered = !snapshots.is_empty();
+        for reply in snapshots {
+            _ = reply.send(Snapshot {
+                width: surface.width(),
+                height: surface.height(),
+                stride: surface.stride(),
+                data: surface.data().unwrap().to_vec(),
+            });
+        }
+        if answered {
+            shown.wake();
+        }
+        if scene.is_none() && fade.is_none() {
+            continue;
+        }
+        let fade_alpha = fade.as_ref().map_or(0.0, |fade| {
+            1.0 - fade.started.elapsed().as_millis() as f64 / fade.duration_ms as f64
+        });
+        if fade.is_some() {
+            clips = vec![ClipRect::new(0, 0, height, width)];
+        }
+        let data = match &fade {
+            Some(from) if fade_alpha > 0.0 => blend_surfaces(&surface, &from.from, fade_alpha).data().unwrap().to_vec(),
+            _ => surface.data().unwrap().to_vec(),
+        };
+        if fade_alpha <= 0.0 {
+            fade = None;
+        }
+        counters::add(&FRAMES, 1);
+        if !clips.is_empty() {
+            if let Err(e) = present::show(display, &Frame { data, clips }, bar, &epoll) {
+                _ = errors.send(Failure::Frame(e));
+            }
+        }
+        // also on every frame of a fade, so that a press does not wait for it to end
+        shown.notify();
+    }
+}
+
+// left and right are the centers of the corners, not the edges of the box.
+// Sides that are not rounded get square corners, which is used to join
+// the buttons of a group.
+fn rounded_rect(c: &Context, left: f64, right: f64, bot: f64, top: f64, radius: f64, round_left: bool, round_right: bool) {
+    c.new_sub_path();
+    if round_right {
+        c.arc(
+            right,
+            bot,
+            radius,
+            (-90.0f64).to_radians(),
+            (0.0f64).to_radians(),
+        );
+        c.arc(
+            right,
+            top,
+            radius,
+            (0.0f64).to_radians(),
+            (90.0f64).to_radians(),
+        );
+    } else {
+        c.line_to(right + radius, bot - radius);
+        c.line_to(right + radius, top + radius);
+    }
+    if round_left {
+        c.arc(
+            left,
+            top,
+            radius,
+            (90.0f64).to_radians(),
+            (180.0f64).to_radians(),
+        );
+        c.arc(
+            left,
+            bot,
+            radius,
+            (180.0f64).to_radians(),
+            (270.0f64).to_radians(),
+        );
+    } else {
+        c.line_to(left - radius, top + radius);
+        c.line_to(left - radius, bot - radius);
+    }
+    c.close_path();
+}
+
+// The display is mounted rotated, this makes x go along the bar and y across it
+pub fn transform_to_bar(c: &Context, style: &Style, width: i32, height: i32) {
+    c.translate(height as f64, 0.0);
+    c.rotate((90.0f64).to_radians());
+    if style.flip_horizontal {
+        c.translate(width as f64, 0.0);
+        c.scale(-1.0, 1.0);
+    }
+    if style.flip_vertical {
+        c.translate(0.0, height as f64);
+        c.scale(1.0, -1.0);
+    }
+}
+
+// Draws a message like a notification, centered in the area given by its left edge and width
+fn draw_toast(c: &Context, height: i32, text: &str, area: (f64, f64)) {
+    let (area_left, area_width) = area;
+    c.set_font_size(FONT_SIZE * 0.75);
+    let mut extents = c.text_extents(text).unwrap();
+    let max_width = area_width - 2.0 * TEXT_PADDING_PX;
+    if extents.width() > max_width && max_width > 0.0 {
+        c.set_font_size(FONT_SIZE * 0.75 * max_width / extents.width());
+        extents = c.text_extents(text).unwrap();
+    }
+    let padding = height as f64 * 0.2;
+    let box_width = (extents.width() + padding * 2.0).min(area_width);
+    let left = (area_left + (area_width - box_width) / 2.0).round();
+    c.set_source_rgb(0.0, 0.0, 0.0);
+    c.rectangle(left, 0.0, box_width, height as f64);
+    c.fill().unwrap();
+    c.set_source_rgb(1.0, 1.0, 1.0);
+    c.move_to(
+        (area_left + area_width / 2.0 - extents.width() / 2.0).round(),
+        (height as f64 / 2.0 + extents.height() / 2.0).round()
+    );
+    c.show_text(text).unwrap();
+}
+
+// Width of the strip at the end of the bar taken by the layer indicator,
+// including the gap to the buttons
+pub fn layer_indicator_width(indicator: LayerIndicator) -> f64 {
+    let strip = match indicator {
+        LayerIndicator::None => return 0.0,
+        LayerIndicator::Dots => INDICATOR_DOT_RADIUS_PX * 2.0,
+        LayerIndicator::Label => 80.0,
+    };
+    strip + BUTTON_SPACING_PX as f64
+}
+
+// Dots or the name of the layer in the strip kept free by the layout
+fn draw_layer_indicator(c: &Context, scene: &Scene, height: f64) {
+    let style = &scene.style;
+    let (area_left, area_width) = scene.area;
+    let strip_width = layer_indicator_width(style.layer_indicator) - BUTTON_SPACING_PX as f64;
+    let left = match style.layer_indicator_position {
+        IndicatorPosition::Left => area_left,
+        IndicatorPosition::Right => area_left + area_width - strip_width,
+    };
+    let center = left + strip_width / 2.0;
+    let (index, count) = scene.position;
+    match style.layer_indicator {
+        LayerIndicator::None => {},
+        LayerIndicator::Dots => {
+            let first = height / 2.0 - (count - 1) as f64 * INDICATOR_DOT_SPACING_PX / 2.0;
+            for i in 0..count {
+                let (r, g, b) = if i == index { (1.0, 1.0, 1.0) } else { style.button_style.inactive_color };
+                c.set_source_rgb(r, g, b);
+                c.arc(center, first + i as f64 * INDICATOR_DOT_SPACING_PX, INDICATOR_DOT_RADIUS_PX, 0.0, 360.0f64.to_radians());
+                c.fill().unwrap();
+            }
+        },
+        LayerIndicator::Label => {
+            let name = &scene.layer_name;
+            c.set_font_size(FONT_SIZE / 2.0);
+            let mut extents = c.text_extents(name).unwrap();
+            if extents.width() > strip_width {
+                c.set_font_size(FONT_SIZE / 2.0 * strip_width / extents.width());
+                extents = c.text_extents(name).unwrap();
+            }
+            c.set_source_rgb(1.0, 1.0, 1.0);
+            c.move_to((center - extents.width() / 2.0).round(), (height / 2.0 + extents.height() / 2.0).round());
+            c.show_text(name).unwrap();
+            c.set_font_size(FONT_SIZE);
+        },
+    }
+}
+
+fn draw_image(c: &Context, resources: &mut Resources, button: &ButtonScene, height: i32, y_shift: f64) {
+    let button_left_edge = button.left_edge;
+    let button_width = button.width.ceil();
+    let icon = match &button.image {
+        Image::Text(text) => {
+            let mut extents = c.text_extents(text).unwrap();
+            // labels that do not fit on narrow buttons are shrunk instead of
+            // running into the edges of the box
+            let max_width = button_width - 2.0 * TEXT_PADDING_PX;
+            if extents.width() > max_width && max_width > 0.0 {
+                c.set_font_size(FONT_SIZE * max_width / extents.width());
+                extents = c.text_extents(text).unwrap();
+            }
+            c.move_to(
+                button_left_edge + (button_width / 2.0 - extents.width() / 2.0).round(),
+                y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round()
+            );
+            c.show_text(text).unwrap();
+            c.set_font_size(FONT_SIZE);
+            return;
+        },
+        Image::Icon(source) => resources.icon(source),
+    };
+    let x = button_left_edge + (button_width / 2.0 - (ICON_SIZE / 2) as f64).round();
+    let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
+    match icon {
+        None => {},
+        Some(LoadedIcon::Svg(svg)) => {
+            let renderer = CairoRenderer::new(svg);
+            if let Some((r, g, b)) = button.icon_color {
+                let mask = ImageSurface::create(Format::ARgb32, ICON_SIZE, ICON_SIZE).unwrap();
+                renderer.render_document(&Context::new(&mask).unwrap(),
+                    &Rectangle::new(0.0, 0.0, ICON_SIZE as f64, ICON_SIZE as f64)
+                ).unwrap();
+                c.set_source_rgb(r, g, b);
+                c.mask_surface(&mask, x, y).unwrap();
+            } else {
+                renderer.render_document(c,
+                    &Rectangle::new(x, y, ICON_SIZE as f64, ICON_SIZE as f64)
+                ).unwrap();
+            }
+        }
+        Some(LoadedIcon::Bitmap(surf)) => {
+            if let Some((r, g, b)) = button.icon_color {
+                c.set_source_rgb(r, g, b);
+                c.mask_surface(surf, x, y).unwrap();
+            } else {
+                c.set_source_surface(surf, x, y).unwrap();
+                c.rectangle(x, y, ICON_SIZE as f64, ICON_SIZE as f64);
+                c.fill().unwrap();
+            }
+        }
+    }
+}
+
+// Draws the buttons that changed, or everything, and returns the regions of the display that need updating
+fn draw(scene: &Scene, surface: &ImageSurface, resources: &mut Resources, width: i32, height: i32, complete: bool) -> Vec<ClipRect> {
+    let style = &scene.style;
+    let complete_redraw = complete || scene.complete;
+    let c = Context::new(surface).unwrap();
+    let mut modified_regions = if complete_redraw {
+        vec![ClipRect::new(0, 0, height as u16, width as u16)]
+    } else {
+        Vec::new()
+    };
+    transform_to_bar(&c, style, width, height);
+    let radius = 8.0f64;
+    let bot = (height as f64) * 0.15;
+    let top = (height as f64) * 0.85;
+
+    if complete_redraw {
+        c.set_source_rgb(0.0, 0.0, 0.0);
+        c.paint().unwrap();
+    }
+    if let Some(font) = resources.font(&style.font_template) {
+        c.set_font_face(&font);
+    }
+    c.set_font_size(FONT_SIZE);
+    // the indicator only changes along with the layer, which redraws everything
+    if complete_redraw {
+        draw_layer_indicator(&c, scene, height as f64);
+    }
+    for button in &scene.buttons {
+        if !button.changed && !complete_redraw {
+            continue;
+        }
+        let (left_edge, right_edge) = (button.left_edge, button.right_edge);
+        let (round_left, round_right) = (button.round_left, button.round_right);
+        if !complete_redraw {
+            c.set_source_rgb(0.0, 0.0, 0.0);
+            c.rectangle(left_edge, bot - radius, right_edge - left_edge, top - bot + radius * 2.0);
+            c.fill().unwrap();
+        }
+        let (r, g, b) = button.color;
+        c.set_source_rgb(r, g, b);
+        // draw box with rounded corners
+        let left = left_edge + radius;
+        let right = right_edge - radius;
+        rounded_rect(&c, left, right, bot, top, radius, round_left, round_right);
+        c.fill().unwrap();
+
+        // the effect is drawn inside of the box, so that it does not
+        // spill out of the region that gets redrawn for this button
+        let level = button.level;
+        let (ar, ag, ab) = style.button_style.accent_color;
+        match style.button_style.active_effect {
+            ActiveEffect::None => {},
+            ActiveEffect::Border => {
+                let width = ACTIVE_BORDER_WIDTH_PX;
+                c.set_source_rgba(ar, ag, ab, level);
+                c.set_line_width(width);
+                rounded_rect(&c, left, right, bot, top, radius - width / 2.0, round_left, round_right);
+                c.stroke().unwrap();
+            },
+            ActiveEffect::Glow => {
+                c.set_line_width(1.0);
+                for i in 0..ACTIVE_GLOW_WIDTH_PX {
+                    let falloff = 1.0 - i as f64 / ACTIVE_GLOW_WIDTH_PX as f64;
+                    c.set_source_rgba(ar, ag, ab, level * falloff * falloff);
+                    rounded_rect(&c, left, right, bot, top, radius - i as f64 - 0.5, round_left, round_right);
+                    c.stroke().unwrap();
+                }
+            },
+            ActiveEffect::Inset => {
+                let edge = bot - radius;
+                let shadow = LinearGradient::new(0.0, edge, 0.0, edge + ACTIVE_INSET_DEPTH_PX);
+                shadow.add_color_stop_rgba(0.0, 0.0, 0.0, 0.0, ACTIVE_INSET_ALPHA * level);
+                shadow.add_color_stop_rgba(1.0, 0.0, 0.0, 0.0, 0.0);
+                c.set_source(&shadow).unwrap();
+                rounded_rect(&c, left, right, bot, top, radius, round_left, round_right);
+                c.fill().unwrap();
+            },
+        }
+
+        c.set_source_rgb(1.0, 1.0, 1.0);
+        draw_image(&c, resources, button, height, scene.y_shift - level * style.button_style.bounce);
+
+        // inside of the box, like the effects
+        if button.progress > 0.0 {
+            c.set_source_rgb(ar, ag, ab);
+            c.rectangle(left, top + radius - HOLD_PROGRESS_OFFSET_PX, (right - left) * button.progress, HOLD_PROGRESS_WIDTH_PX);
+            c.fill().unwrap();
+        }
+
+        // each button covers its own part of the dividers on both of its sides,
+        // so that redrawing only one of two neighbours keeps the divider whole
+        if style.edge_to_edge && style.divider_width > 0 {
+            let (before, after) = (style.divider_width / 2, style.divider_width - style.divider_width / 2);
+            c.set_source_rgb(0.0, 0.0, 0.0);
+            if !round_left {
+                c.rectangle(left_edge, bot, after as f64, top - bot);
+            }
+            if !round_right {
+                c.rectangle(right_edge - before as f64, bot, before as f64, top - bot);
+            }
+            c.fill().unwrap();
+        }
+
+        if !complete_redraw {
+            // the region is in the coordinates of the display, before transform_to_bar
+            let (mut x1, mut x2) = (height as u16 - top as u16 - radius as u16, height as u16 - bot as u16 + radius as u16);
+            // the box ends at right_edge, which can be a pixel further than the width says
+            let (mut y1, mut y2) = (left_edge as u16, right_edge.ceil() as u16);
+            if style.flip_vertical {
+                (x1, x2) = (height as u16 - x2, height as u16 - x1);
+            }
+            if style.flip_horizontal {
+                (y1, y2) = (width as u16 - y2, width as u16 - y1);
+            }
+            modified_regions.push(ClipRect::new(x1, y1, x2, y2));
+        }
+    }
+    if let Some((text, area)) = &scene.toast {
+        draw_toast(&c, height, text, *area);
+    }
+
+    modified_regions
+}
+
+fn copy_surface(surface: &ImageSurface) -> ImageSurface {
+    let out = ImageSurface::create(Format::ARgb32, surface.width(), surface.height()).unwrap();
+    let c = Context::new(&out).unwrap();
+    c.set_source_surface(surface, 0.0, 0.0).unwrap();
+    c.paint().unwrap();
+    drop(c);
+    out
+}
+
+// Paints `top` with the given opacity over `bottom` into a new surface
+fn blend_surfaces(bottom: &ImageSurface, top: &ImageSurface, alpha: f64) -> ImageSurface {
+    let out = ImageSurface::create(Format::ARgb32, bottom.width(), bottom.height()).unwrap();
+    let c = Context::new(&out).unwrap();
+    c.set_source_surface(bottom, 0.0, 0.0).unwrap();
+    c.paint().unwrap();
+    c.set_source_surface(top, 0.0, 0.0).unwrap();
+    c.paint_with_alpha(alpha).unwrap();
+    drop(c);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The numpad layer of the shipped config, where it is commented out as an example
+    fn numpad_example() -> String {
+        include_str!("../share/tiny-dfr/config.toml").lines()
+            .skip_while(|line| !line.starts_with("#ExtraLayers.Numpad"))
+            .take_while(|line| line.starts_with('#'))
+            .map(|line| &line[1..])
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn labels_of_18_buttons_stay_inside_their_boxes() {
+        let (width, height) = (2008, 60);
+        let (mut cfg, mut layers) = config::test_layers(&numpad_example(), width as u16);
+        cfg.layer_indicator = LayerIndicator::None;
+        cfg.edge_to_edge = false;
+        cfg.enable_pixel_shift = false;
+        let numpad = layers.iter_mut().find(|layer| layer.name == "Numpad").unwrap();
+        assert_eq!(numpad.buttons.len(), 18);
+        let buttons = numpad.scene(&cfg, width as u16, (0.0, 0.0), &[], true);
+        let scene = Scene {
+            style: Arc::new(Style::from_config(&cfg)),
+            layer: 0,
+            layer_name: "Numpad".to_string(),
+            position: (0, 1),
+            buttons: buttons.clone(),
+            area: (0.0, width as f64),
+            y_shift: 0.0,
+            complete: true,
+            toast: None,
+        };
+        // the display is mounted rotated, see transform_to_bar
+        let mut surface = ImageSurface::create(Format::ARgb32, height, width).unwrap();
+        let mut resources = Resources { font_template: None, font: None, icons: HashMap::new(), errors: mpsc::channel().0 };
+        draw(&scene, &surface, &mut resources, width, height, true);
+        let stride = surface.stride() as usize;
+        let data = surface.data().unwrap();
+        let red = |x: i32, y: i32| data[x as usize * stride + (height - 1 - y) as usize * 4 + 2];
+        // rows where the sides of the boxes are straight
+        let (bot, top) = ((height as f64 * 0.15) as i32 + 8, (height as f64 * 0.85) as i32 - 8);
+        for button in &buttons {
+            let Image::Text(label) = &button.image else {
+                panic!("the numpad has text buttons only");
+            };
+            let box_red = (button.color.0 * 255.0).round() as u8;
+            let (left, right) = (button.left_edge as i32, button.right_edge as i32);
+            let margin = (TEXT_PADDING_PX / 2.0) as i32;
+            for y in bot..top {
+                for x in (left..left + margin).chain(right - margin..right) {
+                    assert!(red(x, y) <= box_red + 1, "{} reaches the edge of its box at ({}, {})", label, x, y);
+                }
+            }
+            let drawn = (bot..top).any(|y| (left..right).any(|x| red(x, y) > 128));
+            assert!(drawn, "{} is not drawn", label);
+        }
+    }
+}
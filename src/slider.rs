@@ -0,0 +1,146 @@
+use std::time::Instant;
+use input_linux::Key;
+use crate::config::SliderConfig;
+
+const DEFAULT_STEP_PX: f64 = 40.0;
+
+pub struct Slider {
+    pub decrease: Key,
+    pub increase: Key,
+    step: f64,
+    // how long a touch needs to rest on the slider before moving adjusts it
+    dwell_ms: u32,
+    started: Instant,
+    // x position at which the last step was taken, while touched
+    anchor: Option<f64>,
+    moved: bool,
+    // steps that were taken but not emitted yet, positive is increase
+    pending: i32,
+    last_flush: Instant,
+}
+
+impl Slider {
+    pub fn with_config(cfg: &SliderConfig) -> Slider {
+        Slider {
+            decrease: cfg.decrease,
+            increase: cfg.increase,
+            step: cfg.step.unwrap_or(DEFAULT_STEP_PX).max(1.0),
+            dwell_ms: cfg.dwell.unwrap_or(0),
+            started: Instant::now(),
+            anchor: None,
+            moved: false,
+            pending: 0,
+            last_flush: Instant::now(),
+        }
+    }
+    pub fn start(&mut self, x: f64) {
+        self.anchor = Some(x);
+        self.moved = false;
+        self.started = Instant::now();
+    }
+    fn dwelled(&self) -> bool {
+        self.started.elapsed().as_millis() >= self.dwell_ms as u128
+    }
+    // Returns the number of steps taken since the last call.
+    // Until the touch dwelled, the steps are counted from wherever it is.
+    pub fn motion(&mut self, x: f64) -> i32 {
+        let Some(anchor) = self.anchor else {
+            return 0;
+        };
+        if !self.dwelled() {
+            self.anchor = Some(x);
+            return 0;
+        }
+        let steps = ((x - anchor) / self.step).trunc() as i32;
+        if steps != 0 {
+            self.anchor = Some(anchor + steps as f64 * self.step);
+            self.moved = true;
+        }
+        steps
+    }
+    // Returns whether the touch was for adjusting the value, otherwise it was a tap.
+    // With a Dwell, a touch that rested long enough is no tap even if it did not move.
+    pub fn end(&mut self) -> bool {
+        self.anchor = None;
+        self.moved || (self.dwell_ms > 0 && self.dwelled())
+    }
+    pub fn queue(&mut self, steps: i32) {
+        self.pending += steps;
+    }
+    // Emits at most SliderMaxTaps per SliderCoalesceWindow, keeping the rest
+    // for later so that the total adjustment is the same as without coalescing.
+    // Returns the steps to emit now and when to flush next.
+    pub fn flush(&mut self, window_ms: u32, max_taps: u32) -> (i32, i32) {
+        if self.pending == 0 {
+            return (0, i32::MAX);
+        }
+        let window = window_ms as i32;
+        let since_last_flush = self.last_flush.elapsed().as_millis() as i32;
+        if since_last_flush < window {
+            return (0, window - since_last_flush);
+        }
+        self.last_flush = Instant::now();
+        let max = max_taps as i32;
+        let steps = self.pending.clamp(-max, max);
+        self.pending -= steps;
+        (steps, if self.pending == 0 { i32::MAX } else { window })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn slider(dwell: u32) -> Slider {
+        Slider::with_config(&SliderConfig { decrease: Key::BrightnessDown, increase: Key::BrightnessUp, step: Some(40.0), dwell: Some(dwell) })
+    }
+
+    #[test]
+    fn moving_before_the_dwell_does_not_adjust() {
+        let mut slider = slider(300);
+        slider.start(100.0);
+        assert_eq!(slider.motion(180.0), 0);
+        assert_eq!(slider.motion(260.0), 0);
+        // released right away it is a tap
+        assert!(!slider.end());
+    }
+
+    #[test]
+    fn dragging_after_the_dwell_adjusts() {
+        let mut slider = slider(300);
+        slider.start(100.0);
+        assert_eq!(slider.motion(120.0), 0);
+        slider.started -= Duration::from_millis(300);
+        // counted from where the touch rested, not from where it started
+        assert_eq!(slider.motion(200.0), 2);
+        assert_eq!(slider.motion(150.0), -1);
+        assert!(slider.end());
+    }
+
+    #[test]
+    fn fast_drags_emit_a_bounded_number_of_taps() {
+        let mut slider = slider(0);
+        slider.start(0.0);
+        // across the whole bar in one motion event
+        let steps = slider.motion(2000.0);
+        assert_eq!(steps, 50);
+        slider.queue(steps);
+        slider.last_flush -= Duration::from_millis(50);
+        let mut emitted = Vec::new();
+        loop {
+            let (steps, next_ms) = slider.flush(50, 4);
+            emitted.push(steps);
+            // nothing more within the same window
+            assert_eq!(slider.flush(50, 4).0, 0);
+            if next_ms == i32::MAX {
+                break;
+            }
+            assert_eq!(next_ms, 50);
+            slider.last_flush -= Duration::from_millis(50);
+        }
+        assert!(emitted.iter().all(|&steps| steps <= 4));
+        assert_eq!(emitted.len(), 13);
+        assert_eq!(emitted.iter().sum::<i32>(), 50);
+    }
+}
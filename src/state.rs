@@ -0,0 +1,75 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// What the buttons were left at, restored on the next start:
+// the entry of every Cycle button and the layer picked with a Layer action
+// on each touchbar, as well as quiet mode
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct SavedState {
+    pub quiet: bool,
+    pub bars: Vec<BarState>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct BarState {
+    pub layer: Option<String>,
+    // Text of the current entry by layer name and position of the button
+    pub cycles: BTreeMap<String, BTreeMap<usize, String>>,
+}
+
+pub struct StateFile {
+    file: Option<File>,
+    saved: SavedState,
+}
+
+impl StateFile {
+    // Needs to be opened before privileges are dropped, like the stats files
+    pub fn new(path: Option<&str>) -> StateFile {
+        let Some(path) = path else {
+            return StateFile { file: None, saved: SavedState::default() };
+        };
+        let mut file = match OpenOptions::new().read(true).write(true).create(true).open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("Failed to open state file {}: {}", path, e);
+                return StateFile { file: None, saved: SavedState::default() };
+            }
+        };
+        let mut saved = SavedState::default();
+        let mut contents = String::new();
+        if file.read_to_string(&mut contents).is_ok() && !contents.trim().is_empty() {
+            match serde_json::from_str(&contents) {
+                Ok(state) => saved = state,
+                Err(e) => println!("Ignoring unreadable state file: {}", e),
+            }
+        }
+        StateFile { file: Some(file), saved }
+    }
+    pub fn enabled(&self) -> bool {
+        self.file.is_some()
+    }
+    pub fn saved(&self) -> &SavedState {
+        &self.saved
+    }
+    // Only writes when something changed since the last save
+    pub fn save(&mut self, state: SavedState) -> Result<()> {
+        let Some(mut file) = self.file.as_ref() else {
+            return Ok(());
+        };
+        if state == self.saved {
+            return Ok(());
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(serde_json::to_string_pretty(&state)?.as_bytes())?;
+        self.saved = state;
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+const CHECKPOINT_INTERVAL_MS: u64 = 5 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub struct ButtonStats {
+    pub presses: u64,
+    pub active_ms: u64,
+}
+
+// Counters are keyed by layer name and then by button label, so that
+// reloading a config with a different layout does not merge the counts
+// of unrelated buttons that happen to share an index.
+type StatsMap = BTreeMap<String, BTreeMap<String, ButtonStats>>;
+
+pub struct UsageStats {
+    buttons: StatsMap,
+    file: Option<File>,
+    dirty: bool,
+    last_checkpoint: Instant,
+    // counts of the current run only, written out on shutdown
+    session: StatsMap,
+    session_file: Option<File>,
+}
+
+fn open_file(path: Option<&str>) -> Option<File> {
+    let path = path?;
+    match OpenOptions::new().read(true).write(true).create(true).open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            println!("Failed to open stats file {}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn write_json(mut file: &File, stats: &StatsMap) -> Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(serde_json::to_string_pretty(stats)?.as_bytes())?;
+    Ok(())
+}
+
+impl UsageStats {
+    // The files have to be opened before privileges are dropped,
+    // so the paths are only read once at startup.
+    pub fn new(path: Option<&str>, session_path: Option<&str>) -> UsageStats {
+        let file = open_file(path);
+        let session_file = open_file(session_path);
+        let mut buttons = StatsMap::new();
+        if let Some(mut file) = file.as_ref() {
+            let mut contents = String::new();
+            if file.read_to_string(&mut contents).is_ok() && !contents.trim().is_empty() {
+                match serde_json::from_str(&contents) {
+                    Ok(saved) => buttons = saved,
+                    Err(e) => println!("Ignoring unreadable stats file: {}", e)
+                }
+            }
+        }
+        UsageStats {
+            buttons, file, session_file,
+            dirty: false,
+            last_checkpoint: Instant::now(),
+            session: StatsMap::new(),
+        }
+    }
+    fn entries(&mut self, layer: &str, label: &str) -> [&mut ButtonStats; 2] {
+        self.dirty = true;
+        [&mut self.buttons, &mut self.session].map(|stats| {
+            stats.entry(layer.to_string()).or_default().entry(label.to_string()).or_default()
+        })
+    }
+    pub fn record_press(&mut self, layer: &str, label: &str) {
+        for entry in self.entries(layer, label) {
+            entry.presses += 1;
+        }
+    }
+    pub fn record_release(&mut self, layer: &str, label: &str, held: Duration) {
+        for entry in self.entries(layer, label) {
+            entry.active_ms += held.as_millis() as u64;
+        }
+    }
+    pub fn reset(&mut self) {
+        self.buttons.clear();
+        self.session.clear();
+        self.dirty = true;
+    }
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(&self.buttons).unwrap()
+    }
+    pub fn save(&mut self) -> Result<()> {
+        let Some(file) = self.file.as_ref() else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        write_json(file, &self.buttons)?;
+        self.dirty = false;
+        self.last_checkpoint = Instant::now();
+        Ok(())
+    }
+    pub fn save_session(&self) -> Result<()> {
+        let Some(file) = self.session_file.as_ref() else {
+            return Ok(());
+        };
+        write_json(file, &self.session)
+    }
+    pub fn checkpoint(&mut self) {
+        if (self.last_checkpoint.elapsed().as_millis() as u64) < CHECKPOINT_INTERVAL_MS {
+            return;
+        }
+        if let Err(e) = self.save() {
+            println!("Failed to save stats: {}", e);
+        }
+        self.last_checkpoint = Instant::now();
+    }
+}
@@ -0,0 +1,155 @@
+use input_linux::Key;
+
+// There is no way to ask the kernel to insert a character directly, so text
+// is typed as a sequence of key strokes. The virtual device has no keymap of
+// its own, so the compositor interprets the key codes with the active layout
+// of the seat. The mapping below assumes a US QWERTY layout.
+// Characters outside of ASCII are entered with the Ctrl+Shift+U <hex> Space
+// sequence, which is understood by GTK and IBus based input methods, but not
+// by every application.
+
+pub struct Stroke {
+    pub key: Key,
+    pub shift: bool,
+    pub ctrl: bool,
+}
+
+const LETTERS: [Key; 26] = [
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+];
+
+const DIGITS: [Key; 10] = [
+    Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4,
+    Key::Num5, Key::Num6, Key::Num7, Key::Num8, Key::Num9,
+];
+
+const OTHER_KEYS: [Key; 16] = [
+    Key::Minus, Key::Equal, Key::LeftBrace, Key::RightBrace, Key::Semicolon,
+    Key::Apostrophe, Key::Grave, Key::Backslash, Key::Comma, Key::Dot,
+    Key::Slash, Key::Space, Key::Enter, Key::Tab,
+    Key::LeftShift, Key::LeftCtrl,
+];
+
+// Every key that a Text action may emit, these need to be registered
+// on the uinput device before it is created.
+pub fn text_keys() -> impl Iterator<Item = Key> {
+    LETTERS.into_iter().chain(DIGITS).chain(OTHER_KEYS)
+}
+
+fn ascii_key(c: char) -> Option<(Key, bool)> {
+    let key = match c {
+        'a'..='z' => (LETTERS[c as usize - 'a' as usize], false),
+        'A'..='Z' => (LETTERS[c as usize - 'A' as usize], true),
+        '0'..='9' => (DIGITS[c as usize - '0' as usize], false),
+        ')' => (Key::Num0, true),
+        '!' => (Key::Num1, true),
+        '@' => (Key::Num2, true),
+        '#' => (Key::Num3, true),
+        '$' => (Key::Num4, true),
+        '%' => (Key::Num5, true),
+        '^' => (Key::Num6, true),
+        '&' => (Key::Num7, true),
+        '*' => (Key::Num8, true),
+        '(' => (Key::Num9, true),
+        '-' => (Key::Minus, false),
+        '_' => (Key::Minus, true),
+        '=' => (Key::Equal, false),
+        '+' => (Key::Equal, true),
+        '[' => (Key::LeftBrace, false),
+        '{' => (Key::LeftBrace, true),
+        ']' => (Key::RightBrace, false),
+        '}' => (Key::RightBrace, true),
+        ';' => (Key::Semicolon, false),
+        ':' => (Key::Semicolon, true),
+        '\'' => (Key::Apostrophe, false),
+        '"' => (Key::Apostrophe, true),
+        '`' => (Key::Grave, false),
+        '~' => (Key::Grave, true),
+        '\\' => (Key::Backslash, false),
+        '|' => (Key::Backslash, true),
+        ',' => (Key::Comma, false),
+        '<' => (Key::Comma, true),
+        '.' => (Key::Dot, false),
+        '>' => (Key::Dot, true),
+        '/' => (Key::Slash, false),
+        '?' => (Key::Slash, true),
+        ' ' => (Key::Space, false),
+        '\n' => (Key::Enter, false),
+        '\t' => (Key::Tab, false),
+        _ => return None,
+    };
+    Some(key)
+}
+
+pub fn strokes(text: &str) -> Vec<Stroke> {
+    let mut strokes = Vec::new();
+    for c in text.chars() {
+        if let Some((key, shift)) = ascii_key(c) {
+            strokes.push(Stroke { key, shift, ctrl: false });
+            continue;
+        }
+        strokes.push(Stroke { key: Key::U, shift: true, ctrl: true });
+        for digit in format!("{:x}", c as u32).chars() {
+            let (key, _) = ascii_key(digit).unwrap();
+            strokes.push(Stroke { key, shift: false, ctrl: false });
+        }
+        strokes.push(Stroke { key: Key::Space, shift: false, ctrl: false });
+    }
+    strokes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The keys of a US QWERTY layout with what they type without and with Shift
+    const US_LAYOUT: [(Key, char, char); 21] = [
+        (Key::Grave, '`', '~'), (Key::Num1, '1', '!'), (Key::Num2, '2', '@'),
+        (Key::Num3, '3', '#'), (Key::Num4, '4', '$'), (Key::Num5, '5', '%'),
+        (Key::Num6, '6', '^'), (Key::Num7, '7', '&'), (Key::Num8, '8', '*'),
+        (Key::Num9, '9', '('), (Key::Num0, '0', ')'), (Key::Minus, '-', '_'),
+        (Key::Equal, '=', '+'), (Key::LeftBrace, '[', '{'), (Key::RightBrace, ']', '}'),
+        (Key::Backslash, '\\', '|'), (Key::Semicolon, ';', ':'), (Key::Apostrophe, '\'', '"'),
+        (Key::Comma, ',', '<'), (Key::Dot, '.', '>'), (Key::Slash, '/', '?'),
+    ];
+
+    fn expected(c: char) -> (Key, bool) {
+        if c == ' ' {
+            return (Key::Space, false);
+        }
+        if c.is_ascii_alphabetic() {
+            let key = LETTERS[c.to_ascii_lowercase() as usize - 'a' as usize];
+            return (key, c.is_ascii_uppercase());
+        }
+        US_LAYOUT.iter()
+            .find_map(|&(key, plain, shifted)| {
+                if c == plain { Some((key, false)) } else if c == shifted { Some((key, true)) } else { None }
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn printable_ascii_is_a_single_stroke() {
+        let keys: Vec<Key> = text_keys().collect();
+        for c in (0x20u8..0x7f).map(char::from) {
+            let strokes = strokes(&c.to_string());
+            assert_eq!(strokes.len(), 1, "{:?}", c);
+            let (key, shift) = expected(c);
+            assert_eq!((strokes[0].key, strokes[0].shift, strokes[0].ctrl), (key, shift, false), "{:?}", c);
+            assert!(keys.contains(&key), "{:?}", c);
+        }
+    }
+
+    #[test]
+    fn other_characters_are_entered_as_hex() {
+        let strokes: Vec<(Key, bool, bool)> = strokes("é").into_iter().map(|s| (s.key, s.shift, s.ctrl)).collect();
+        assert_eq!(strokes, vec![
+            (Key::U, true, true),
+            (Key::E, false, false),
+            (Key::Num9, false, false),
+            (Key::Space, false, false),
+        ]);
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    fs::{self, File},
+    os::unix::fs::FileExt,
+    time::Instant,
+};
+use anyhow::Result;
+
+const ACTIVE_VT_PATH: &'static str = "/sys/class/tty/tty0/active";
+const SESSIONS_PATH: &'static str = "/run/systemd/sessions";
+const SESSION_RECHECK_MS: i32 = 1000;
+
+fn read_active(file: &File) -> Option<String> {
+    let mut buf = [0u8; 32];
+    let len = file.read_at(&mut buf, 0).ok()?;
+    Some(String::from_utf8_lossy(&buf[..len]).trim().to_string())
+}
+
+// Whether logind has a graphical session, of a user or of a greeter, on the
+// given VT. The session files are plain KEY=VALUE lines readable by anyone,
+// which avoids talking to logind over D-Bus. They are internal to logind
+// though, unlike sd-login or its D-Bus API, so a systemd version that
+// changes them makes this find no sessions, as if there was no logind.
+fn graphical_session_on(vt: &str) -> bool {
+    let Some(vtnr) = vt.strip_prefix("tty") else {
+        return false;
+    };
+    let Ok(entries) = fs::read_dir(SESSIONS_PATH) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        // skip the .ref fifos next to the session files, opening them would block
+        if entry.file_name().to_string_lossy().contains('.') {
+            return false;
+        }
+        let Ok(contents) = fs::read_to_string(entry.path()) else {
+            return false;
+        };
+        let field = |key: &str| contents.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix('='));
+        field("VTNR") == Some(vtnr)
+            && matches!(field("TYPE"), Some("x11" | "wayland" | "mir"))
+            && field("STATE") != Some("closing")
+    })
+}
+
+// Follows which VT is shown, to let go of the touchbar while another VT than
+// the one of the session is. The kernel notifies pollers of the sysfs
+// attribute with EPOLLPRI whenever it changes, so no logind session is needed.
+pub struct VtWatcher {
+    file: File,
+    session_vt: String,
+    // with a SessionVt of 0, the session moves to whichever VT a graphical one is shown on
+    follow_sessions: bool,
+    shown_vt: String,
+    checked_at: Instant,
+    active: bool,
+}
+
+impl VtWatcher {
+    // A session_vt of 0 stands for the VT that is shown when tiny-dfr starts,
+    // until a graphical session is shown on another one
+    pub fn new(session_vt: u32) -> Result<VtWatcher> {
+        let file = File::open(ACTIVE_VT_PATH)?;
+        let shown_vt = read_active(&file).unwrap_or_default();
+        let follow_sessions = session_vt == 0;
+        let session_vt = match session_vt {
+            0 => shown_vt.clone(),
+            vt => format!("tty{}", vt),
+        };
+        Ok(VtWatcher {
+            file,
+            session_vt,
+            follow_sessions,
+            shown_vt,
+            checked_at: Instant::now(),
+            active: true,
+        })
+    }
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+    // Needs to be called after every wakeup, reading the attribute is what
+    // makes it wait for the next change
+    pub fn update(&mut self) {
+        let Some(shown_vt) = read_active(&self.file) else {
+            return;
+        };
+        // After logging in from a greeter the session of the user can show up
+        // a moment after its VT, so a VT without one is checked again every second
+        let recheck = !self.active && self.checked_at.elapsed().as_millis() as i32 >= SESSION_RECHECK_MS;
+        if self.follow_sessions && (shown_vt != self.shown_vt || recheck) {
+            if shown_vt != self.session_vt && graphical_session_on(&shown_vt) {
+                println!("Following the graphical session to {}", shown_vt);
+                self.session_vt = shown_vt.clone();
+            }
+            self.checked_at = Instant::now();
+        }
+        self.active = shown_vt == self.session_vt;
+        self.shown_vt = shown_vt;
+    }
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+    pub fn next_timeout_ms(&self) -> i32 {
+        if !self.follow_sessions || self.active {
+            return i32::MAX;
+        }
+        (SESSION_RECHECK_MS - self.checked_at.elapsed().as_millis() as i32).max(0)
+    }
+}
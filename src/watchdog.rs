@@ -0,0 +1,67 @@
+use std::time::Instant;
+use crate::display::DrmBackend;
+
+// Firmware can get stuck showing a stale frame while everything still reports
+// success, so the display is rebuilt from scratch when a check fails. Attempts
+// are spaced out so a display that is gone for good does not cause a busy loop.
+const RECOVERY_INTERVAL_MS: i32 = 30 * 1000;
+
+pub struct DisplayWatchdog {
+    last_check: Instant,
+    last_recovery: Option<Instant>,
+    failed: bool,
+}
+
+impl DisplayWatchdog {
+    pub fn new() -> DisplayWatchdog {
+        DisplayWatchdog {
+            last_check: Instant::now(),
+            last_recovery: None,
+            failed: false,
+        }
+    }
+    pub fn commit_failed(&mut self) {
+        self.failed = true;
+    }
+    // Returns whether the display was rebuilt and needs a complete redraw,
+    // and when to check next
+    pub fn update(&mut self, drm: &mut DrmBackend, interval_ms: u32) -> (bool, i32) {
+        if interval_ms == 0 {
+            return (false, i32::MAX);
+        }
+        let interval_ms = interval_ms as i32;
+        if !self.failed {
+            let since_last_check = self.last_check.elapsed().as_millis() as i32;
+            if since_last_check < interval_ms {
+                return (false, interval_ms - since_last_check);
+            }
+            self.last_check = Instant::now();
+            match drm.check() {
+                Ok(()) => return (false, interval_ms),
+                Err(e) => {
+                    println!("Display check failed: {}", e);
+                    self.failed = true;
+                }
+            }
+        }
+        if let Some(last_recovery) = self.last_recovery {
+            let since_last_recovery = last_recovery.elapsed().as_millis() as i32;
+            if since_last_recovery < RECOVERY_INTERVAL_MS {
+                return (false, RECOVERY_INTERVAL_MS - since_last_recovery);
+            }
+        }
+        self.last_recovery = Some(Instant::now());
+        println!("Re-initializing display");
+        match drm.reinit() {
+            Ok(()) => {
+                self.failed = false;
+                self.last_check = Instant::now();
+                (true, interval_ms)
+            }
+            Err(e) => {
+                println!("Failed to re-initialize display: {}", e);
+                (false, RECOVERY_INTERVAL_MS)
+            }
+        }
+    }
+}